@@ -0,0 +1,379 @@
+//! Sign requests to AWS services with SigV4, using the credentials of the execution role.
+
+use crate::error::ProviderError;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{header::HeaderValue, Client, Request, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{env, sync::Arc};
+
+/// The credentials of the execution role, which Lambda sets as env vars.
+pub struct Credentials {
+  access_key_id: String,
+  secret_access_key: String,
+  session_token: Option<String>,
+}
+
+impl Credentials {
+  /// Return `None` if the access key is not set.
+  pub fn from_env() -> Option<Self> {
+    Some(Credentials {
+      access_key_id: env::var("AWS_ACCESS_KEY_ID").ok()?,
+      secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").ok()?,
+      session_token: env::var("AWS_SESSION_TOKEN").ok(),
+    })
+  }
+}
+
+// don't leak credentials in logs
+impl std::fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Credentials")
+      .field("access_key_id", &self.access_key_id)
+      .finish_non_exhaustive()
+  }
+}
+
+/// The region of the function, which Lambda sets as an env var.
+pub fn region() -> String {
+  env::var("AWS_REGION")
+    .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+    .unwrap_or_else(|_| "us-east-1".to_string())
+}
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Signer {
+  /// Wrapped in an Arc to make [`Self`] cheap to clone.
+  credentials: Arc<Credentials>,
+  region: Arc<String>,
+  /// The signing name of the service, e.g. `s3`.
+  service: &'static str,
+}
+
+impl Signer {
+  pub fn new(credentials: Arc<Credentials>, region: String, service: &'static str) -> Self {
+    Signer {
+      credentials,
+      region: Arc::new(region),
+      service,
+    }
+  }
+
+  pub fn region(&self) -> &str {
+    &self.region
+  }
+
+  /// Add the SigV4 headers to the request, all headers already set are signed.
+  /// The path of the url must be encoded already and is signed as is, like S3 expects,
+  /// and the body must be in memory.
+  pub fn sign(&self, req: &mut Request) {
+    self.sign_at(req, Utc::now())
+  }
+
+  fn sign_at(&self, req: &mut Request, now: DateTime<Utc>) {
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = &amz_date[..8];
+    let payload_hash = format!(
+      "{:x}",
+      Sha256::digest(req.body().and_then(|b| b.as_bytes()).unwrap_or_default())
+    );
+    let host = match (req.url().host_str(), req.url().port()) {
+      (Some(host), Some(port)) => format!("{}:{}", host, port),
+      (host, None) => host.unwrap_or_default().to_string(),
+      (None, Some(_)) => String::new(),
+    };
+
+    let headers = req.headers_mut();
+    headers.insert("x-amz-date", HeaderValue::from_str(&amz_date).unwrap());
+    // only S3 requires the hash of the payload as a header
+    if self.service == "s3" {
+      headers.insert(
+        "x-amz-content-sha256",
+        HeaderValue::from_str(&payload_hash).unwrap(),
+      );
+    }
+    if let Some(Ok(token)) = self
+      .credentials
+      .session_token
+      .as_deref()
+      .map(HeaderValue::from_str)
+    {
+      headers.insert("x-amz-security-token", token);
+    }
+
+    // header names are already lowercase,
+    // values are trimmed and sequential spaces are collapsed to one
+    let mut signed: Vec<_> = headers
+      .iter()
+      .map(|(name, value)| {
+        let value = value.to_str().unwrap_or_default();
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        (name.as_str().to_string(), value)
+      })
+      .collect();
+    signed.push(("host".to_string(), host));
+    signed.sort();
+    let canonical_headers: String = signed
+      .iter()
+      .map(|(name, value)| format!("{}:{}\n", name, value))
+      .collect();
+    let signed_headers = signed
+      .iter()
+      .map(|(name, _)| name.as_str())
+      .collect::<Vec<_>>()
+      .join(";");
+
+    let mut query: Vec<_> = req
+      .url()
+      .query_pairs()
+      .map(|(k, v)| {
+        (
+          urlencoding::encode(&k).into_owned(),
+          urlencoding::encode(&v).into_owned(),
+        )
+      })
+      .collect();
+    query.sort();
+    let canonical_query = query
+      .iter()
+      .map(|(k, v)| format!("{}={}", k, v))
+      .collect::<Vec<_>>()
+      .join("&");
+
+    let canonical_request = format!(
+      "{}\n{}\n{}\n{}\n{}\n{}",
+      req.method(),
+      req.url().path(),
+      canonical_query,
+      canonical_headers,
+      signed_headers,
+      payload_hash
+    );
+    let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+    let string_to_sign = format!(
+      "AWS4-HMAC-SHA256\n{}\n{}\n{:x}",
+      amz_date,
+      scope,
+      Sha256::digest(&canonical_request)
+    );
+    let key = [date, self.region.as_str(), self.service, "aws4_request"]
+      .iter()
+      .fold(
+        format!("AWS4{}", self.credentials.secret_access_key).into_bytes(),
+        |key, data| hmac(&key, data.as_bytes()),
+      );
+    let signature: String = hmac(&key, string_to_sign.as_bytes())
+      .iter()
+      .map(|b| format!("{:02x}", b))
+      .collect();
+
+    let authorization = format!(
+      "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+      self.credentials.access_key_id, scope, signed_headers, signature
+    );
+    if let Ok(authorization) = HeaderValue::from_str(&authorization) {
+      req.headers_mut().insert("authorization", authorization);
+    }
+  }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any size");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// A client of an AWS service with the JSON protocol, e.g. SSM.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct JsonClient {
+  /// Reqwest client, which is cheap to clone.
+  client: Client,
+  signer: Signer,
+  /// Wrapped in an Arc to make [`Self`] cheap to clone.
+  endpoint: Arc<String>,
+  /// The prefix of the `X-Amz-Target` header, e.g. `AmazonSSM`.
+  target_prefix: &'static str,
+}
+
+/// The error response of the JSON protocol.
+#[derive(Default, Deserialize)]
+struct ErrorResponse {
+  #[serde(rename = "__type", default)]
+  error_type: String,
+  #[serde(alias = "Message", default)]
+  message: String,
+}
+
+impl JsonClient {
+  /// Use `https://{service}.{region}.amazonaws.com` if `endpoint` is not specified.
+  pub fn new(signer: Signer, endpoint: Option<&str>, target_prefix: &'static str) -> Self {
+    let endpoint = match endpoint {
+      Some(endpoint) => endpoint.trim_end_matches('/').to_string(),
+      None => format!("https://{}.{}.amazonaws.com", signer.service, signer.region),
+    };
+    JsonClient {
+      client: Client::new(),
+      signer,
+      endpoint: Arc::new(endpoint),
+      target_prefix,
+    }
+  }
+
+  /// Call the action, e.g. `GetParametersByPath`.
+  /// Resource not found errors are mapped to [`ProviderError::NotFound`], see [`error_of`].
+  pub async fn call<T: DeserializeOwned>(
+    &self,
+    action: &str,
+    body: &impl Serialize,
+  ) -> Result<T, ProviderError> {
+    let body = serde_json::to_vec(body).expect("request body is serializable");
+    let mut req = self
+      .client
+      .post(format!("{}/", self.endpoint))
+      .header("x-amz-target", format!("{}.{}", self.target_prefix, action))
+      .header("content-type", "application/x-amz-json-1.1")
+      .body(body)
+      .build()?;
+    self.signer.sign(&mut req);
+
+    let res = self.client.execute(req).await?;
+    let status = res.status();
+    let bytes = res.bytes().await?;
+    if status == StatusCode::OK {
+      return serde_json::from_slice(&bytes)
+        .map_err(|e| ProviderError::Upstream(format!("invalid response of {}: {}", action, e)));
+    }
+
+    Err(error_of(action, status, &bytes))
+  }
+}
+
+/// Map an error response of the JSON protocol.
+/// Secrets scheduled for deletion are not found, like deleted ones.
+fn error_of(action: &str, status: StatusCode, body: &[u8]) -> ProviderError {
+  // only the type of the error is kept, the message is just matched since it might contain the request
+  let ErrorResponse {
+    error_type,
+    message,
+  } = serde_json::from_slice(body).unwrap_or_default();
+  // the type might be prefixed by the namespace, e.g. `com.amazonaws.ssm#ParameterNotFound`
+  let error_type = error_type.rsplit('#').next().unwrap_or_default();
+  match error_type {
+    "ParameterNotFound" | "ResourceNotFoundException" => ProviderError::NotFound,
+    "InvalidRequestException" if message.contains("marked for deletion") => ProviderError::NotFound,
+    "AccessDeniedException" | "UnrecognizedClientException" | "InvalidSignatureException" => {
+      ProviderError::Unauthorized(format!("{} responded with {}", action, error_type))
+    }
+    _ => ProviderError::Upstream(format!(
+      "{} responded with {} {}",
+      action, status, error_type
+    )),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::TimeZone;
+  use reqwest::Method;
+
+  /// Sign a GET request with the credentials and the date of the AWS SigV4 test suite.
+  fn sign(url: &str, headers: &[(&'static str, &str)]) -> String {
+    let credentials = Credentials {
+      access_key_id: "AKIDEXAMPLE".to_string(),
+      secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+      session_token: None,
+    };
+    let signer = Signer::new(Arc::new(credentials), "us-east-1".to_string(), "service");
+    let mut req = Request::new(Method::GET, url.parse().unwrap());
+    for (name, value) in headers {
+      req
+        .headers_mut()
+        .insert(*name, HeaderValue::from_str(value).unwrap());
+    }
+    let now = Utc.with_ymd_and_hms(2015, 8, 30, 12, 36, 0).unwrap();
+    signer.sign_at(&mut req, now);
+    req.headers()["authorization"].to_str().unwrap().to_string()
+  }
+
+  #[test]
+  fn get_vanilla() {
+    assert_eq!(
+      sign("https://example.amazonaws.com/", &[]),
+      "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+       SignedHeaders=host;x-amz-date, \
+       Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+    );
+  }
+
+  #[test]
+  fn get_vanilla_query_order_key_case() {
+    assert_eq!(
+      sign(
+        "https://example.amazonaws.com/?Param2=value2&Param1=value1",
+        &[]
+      ),
+      "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+       SignedHeaders=host;x-amz-date, \
+       Signature=b97d918cfa904a5beff61c982a1b6f458b799221646efd99d3219ec94cdf2500"
+    );
+  }
+
+  #[test]
+  fn get_header_value_trim() {
+    assert_eq!(
+      sign(
+        "https://example.amazonaws.com/",
+        &[("my-header1", " value1"), ("my-header2", "\"a   b   c\"")]
+      ),
+      "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+       SignedHeaders=host;my-header1;my-header2;x-amz-date, \
+       Signature=acc3ed3afb60bb290fc8d2dd0098b9911fcaa05412b367055dee359757a9c736"
+    );
+  }
+
+  fn error_type(body: &str) -> String {
+    match error_of("GetSecretValue", StatusCode::BAD_REQUEST, body.as_bytes()) {
+      ProviderError::NotFound => "NotFound".to_string(),
+      ProviderError::Unauthorized(_) => "Unauthorized".to_string(),
+      e => e.to_string(),
+    }
+  }
+
+  #[test]
+  fn error_of_not_found() {
+    assert_eq!(
+      error_type(r#"{"__type":"ResourceNotFoundException","Message":"not found"}"#),
+      "NotFound"
+    );
+    assert_eq!(
+      error_type(r#"{"__type":"com.amazonaws.ssm#ParameterNotFound"}"#),
+      "NotFound"
+    );
+    assert_eq!(
+      error_type(
+        r#"{"__type":"InvalidRequestException","Message":"You can't perform this operation on the secret because it was marked for deletion."}"#
+      ),
+      "NotFound"
+    );
+  }
+
+  #[test]
+  fn error_of_others() {
+    assert_eq!(
+      error_type(r#"{"__type":"AccessDeniedException","message":"denied"}"#),
+      "Unauthorized"
+    );
+    assert_eq!(
+      error_type(r#"{"__type":"InvalidRequestException","Message":"invalid"}"#),
+      "upstream error: GetSecretValue responded with 400 Bad Request InvalidRequestException"
+    );
+    assert_eq!(
+      error_type("not json"),
+      "upstream error: GetSecretValue responded with 400 Bad Request "
+    );
+  }
+}
@@ -1,12 +1,65 @@
+pub mod chain;
 pub mod fs;
+pub mod git;
+pub mod gray;
+pub mod local;
 pub mod passthrough;
+pub mod persist;
 pub mod provider;
+pub mod s3;
+pub mod secrets;
+pub mod ssm;
 pub mod target;
 
-#[derive(Clone, Debug)]
+use crate::error::ProviderError;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/config/config_type.rs#L3
+lazy_static! {
+  pub static ref CONFIG_TYPE_TEXT: Arc<String> = Arc::new("text".to_string());
+  pub static ref CONFIG_TYPE_JSON: Arc<String> = Arc::new("json".to_string());
+  pub static ref CONFIG_TYPE_XML: Arc<String> = Arc::new("xml".to_string());
+  pub static ref CONFIG_TYPE_YAML: Arc<String> = Arc::new("yaml".to_string());
+  pub static ref CONFIG_TYPE_HTML: Arc<String> = Arc::new("html".to_string());
+  pub static ref CONFIG_TYPE_PROPERTIES: Arc<String> = Arc::new("properties".to_string());
+  pub static ref CONFIG_TYPE_TOML: Arc<String> = Arc::new("toml".to_string());
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
   content: String,
   md5: String,
+  /// Whether this is a beta (gray) config.
+  #[serde(default)]
+  beta: bool,
+  /// One of the nacos config types, e.g. `json`.
+  #[serde(default = "default_content_type")]
+  content_type: Arc<String>,
+  /// In milliseconds since the unix epoch, `0` if unknown.
+  #[serde(default)]
+  last_modified: i64,
+  /// The revision of the source the config is read from, e.g. the commit in git mode.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  version: Option<Arc<String>>,
+}
+
+// the content might be a secret, don't leak it in logs
+impl std::fmt::Debug for Config {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Config")
+      .field("md5", &self.md5)
+      .field("beta", &self.beta)
+      .field("content_type", &self.content_type)
+      .field("last_modified", &self.last_modified)
+      .field("version", &self.version)
+      .finish_non_exhaustive()
+  }
+}
+
+fn default_content_type() -> Arc<String> {
+  CONFIG_TYPE_TEXT.clone()
 }
 
 impl Config {
@@ -14,9 +67,33 @@ impl Config {
     Config {
       md5: format!("{:x}", md5::compute(&content)),
       content,
+      beta: false,
+      content_type: default_content_type(),
+      last_modified: 0,
+      version: None,
     }
   }
 
+  pub fn with_beta(mut self, beta: bool) -> Self {
+    self.beta = beta;
+    self
+  }
+
+  pub fn with_content_type(mut self, content_type: Arc<String>) -> Self {
+    self.content_type = content_type;
+    self
+  }
+
+  pub fn with_last_modified(mut self, last_modified: i64) -> Self {
+    self.last_modified = last_modified;
+    self
+  }
+
+  pub fn with_version(mut self, version: Arc<String>) -> Self {
+    self.version = Some(version);
+    self
+  }
+
   pub fn content(&self) -> &str {
     &self.content
   }
@@ -24,4 +101,61 @@ impl Config {
   pub fn md5(&self) -> &str {
     &self.md5
   }
+
+  pub fn beta(&self) -> bool {
+    self.beta
+  }
+
+  pub fn content_type(&self) -> &Arc<String> {
+    &self.content_type
+  }
+
+  pub fn last_modified(&self) -> i64 {
+    self.last_modified
+  }
+
+  pub fn version(&self) -> Option<&Arc<String>> {
+    self.version.as_ref()
+  }
+
+  /// The `Content-Type` header of the config, like nacos api v1.
+  pub fn mime(&self) -> &'static str {
+    match self.content_type.as_str() {
+      "json" => "application/json;charset=UTF-8",
+      "xml" => "application/xml;charset=UTF-8",
+      "html" => "text/html;charset=UTF-8",
+      _ => "text/plain;charset=UTF-8",
+    }
+  }
+}
+
+/// Parse a nacos config type, unknown types are treated as text.
+pub fn parse_content_type(s: &str) -> Arc<String> {
+  match s.to_ascii_lowercase().as_str() {
+    "json" => CONFIG_TYPE_JSON.clone(),
+    "xml" => CONFIG_TYPE_XML.clone(),
+    "yaml" | "yml" => CONFIG_TYPE_YAML.clone(),
+    "html" | "htm" => CONFIG_TYPE_HTML.clone(),
+    "properties" => CONFIG_TYPE_PROPERTIES.clone(),
+    "toml" => CONFIG_TYPE_TOML.clone(),
+    _ => CONFIG_TYPE_TEXT.clone(),
+  }
+}
+
+/// Detect the config type from the extension of the data id, e.g. `app.yaml` is `yaml`.
+pub fn content_type_of(data_id: &str) -> Arc<String> {
+  match data_id.rsplit_once('.') {
+    Some((_, ext)) => parse_content_type(ext),
+    None => CONFIG_TYPE_TEXT.clone(),
+  }
+}
+
+/// Return the md5 of the config like nacos does, which is an empty string if the config does not exist.
+/// Return `None` if the md5 is unknown because of other errors.
+pub fn md5_of(result: &Result<Arc<Config>, ProviderError>) -> Option<&str> {
+  match result {
+    Ok(config) => Some(config.md5()),
+    Err(ProviderError::NotFound) => Some(""),
+    Err(_) => None,
+  }
 }
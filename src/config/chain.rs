@@ -0,0 +1,420 @@
+use super::{
+  fs::FsConfigProvider, git::GitConfigProvider, passthrough::PassthroughConfigProvider,
+  persist::CacheEntry, provider::ConfigProvider, s3::S3ConfigProvider,
+  secrets::SecretsConfigProvider, ssm::SsmConfigProvider, target::Target, Config,
+};
+use crate::error::ProviderError;
+use lambda_extension::tracing::warn;
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+/// One of the config providers which can be chained.
+/// [`ConfigProvider`] is not object safe, so providers are listed here instead of boxed.
+#[derive(Clone, Debug)]
+pub enum AnyConfigProvider {
+  Fs(FsConfigProvider),
+  Passthrough(PassthroughConfigProvider),
+  S3(S3ConfigProvider),
+  Ssm(SsmConfigProvider),
+  Secrets(SecretsConfigProvider),
+  Git(GitConfigProvider),
+}
+
+impl AnyConfigProvider {
+  /// The name of the provider in `AWS_LAMBDA_NACOS_ADAPTER_PROVIDERS`.
+  pub fn name(&self) -> &'static str {
+    match self {
+      AnyConfigProvider::Fs(_) => "fs",
+      AnyConfigProvider::Passthrough(_) => "passthrough",
+      AnyConfigProvider::S3(_) => "s3",
+      AnyConfigProvider::Ssm(_) => "ssm",
+      AnyConfigProvider::Secrets(_) => "secrets",
+      AnyConfigProvider::Git(_) => "git",
+    }
+  }
+
+  /// Whether configs can be published to or removed from the provider.
+  pub fn supports_writes(&self) -> bool {
+    matches!(self, AnyConfigProvider::Passthrough(_))
+  }
+}
+
+impl ConfigProvider for AnyConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+      AnyConfigProvider::Passthrough(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+      AnyConfigProvider::S3(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+      AnyConfigProvider::Ssm(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+      AnyConfigProvider::Secrets(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+      AnyConfigProvider::Git(cp) => cp.get(data_id, group, tenant, tag, refresh).await,
+    }
+  }
+
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.begin_refresh(targets).await,
+      AnyConfigProvider::Passthrough(cp) => cp.begin_refresh(targets).await,
+      AnyConfigProvider::S3(cp) => cp.begin_refresh(targets).await,
+      AnyConfigProvider::Ssm(cp) => cp.begin_refresh(targets).await,
+      AnyConfigProvider::Secrets(cp) => cp.begin_refresh(targets).await,
+      AnyConfigProvider::Git(cp) => cp.begin_refresh(targets).await,
+    }
+  }
+
+  async fn end_refresh(&self) {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.end_refresh().await,
+      AnyConfigProvider::Passthrough(cp) => cp.end_refresh().await,
+      AnyConfigProvider::S3(cp) => cp.end_refresh().await,
+      AnyConfigProvider::Ssm(cp) => cp.end_refresh().await,
+      AnyConfigProvider::Secrets(cp) => cp.end_refresh().await,
+      AnyConfigProvider::Git(cp) => cp.end_refresh().await,
+    }
+  }
+
+  async fn publish(
+    &self,
+    target: &Target,
+    content: String,
+    content_type: Option<&str>,
+    cas_md5: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.publish(target, content, content_type, cas_md5).await,
+      AnyConfigProvider::Passthrough(cp) => {
+        cp.publish(target, content, content_type, cas_md5).await
+      }
+      AnyConfigProvider::S3(cp) => cp.publish(target, content, content_type, cas_md5).await,
+      AnyConfigProvider::Ssm(cp) => cp.publish(target, content, content_type, cas_md5).await,
+      AnyConfigProvider::Secrets(cp) => cp.publish(target, content, content_type, cas_md5).await,
+      AnyConfigProvider::Git(cp) => cp.publish(target, content, content_type, cas_md5).await,
+    }
+  }
+
+  async fn remove(&self, target: &Target) -> Result<(), ProviderError> {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.remove(target).await,
+      AnyConfigProvider::Passthrough(cp) => cp.remove(target).await,
+      AnyConfigProvider::S3(cp) => cp.remove(target).await,
+      AnyConfigProvider::Ssm(cp) => cp.remove(target).await,
+      AnyConfigProvider::Secrets(cp) => cp.remove(target).await,
+      AnyConfigProvider::Git(cp) => cp.remove(target).await,
+    }
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.snapshot(),
+      AnyConfigProvider::Passthrough(cp) => cp.snapshot(),
+      AnyConfigProvider::S3(cp) => cp.snapshot(),
+      AnyConfigProvider::Ssm(cp) => cp.snapshot(),
+      AnyConfigProvider::Secrets(cp) => cp.snapshot(),
+      AnyConfigProvider::Git(cp) => cp.snapshot(),
+    }
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    match self {
+      AnyConfigProvider::Fs(cp) => cp.restore(entries).await,
+      AnyConfigProvider::Passthrough(cp) => cp.restore(entries).await,
+      AnyConfigProvider::S3(cp) => cp.restore(entries).await,
+      AnyConfigProvider::Ssm(cp) => cp.restore(entries).await,
+      AnyConfigProvider::Secrets(cp) => cp.restore(entries).await,
+      AnyConfigProvider::Git(cp) => cp.restore(entries).await,
+    }
+  }
+}
+
+/// How [`ChainConfigProvider`] picks the result from its providers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainPolicy {
+  /// Return the first config found, providers which fail are skipped.
+  /// If no provider has the config, the first error other than [`ProviderError::NotFound`] is returned.
+  FirstFound,
+  /// Use the first provider, the next one is only tried if it fails.
+  /// A config not found in the primary provider is not looked up in the fallback.
+  Fallback,
+  /// Providers before the last one are override layers, a config not found in a layer is looked up in the next one.
+  /// Unlike [`Self::FirstFound`], an error is returned as is,
+  /// so an override is never silently replaced by the config below it.
+  Override,
+}
+
+impl FromStr for ChainPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "first-found" => Ok(ChainPolicy::FirstFound),
+      "fallback" => Ok(ChainPolicy::Fallback),
+      "override" => Ok(ChainPolicy::Override),
+      _ => Err(format!("unknown chain policy: {}", s)),
+    }
+  }
+}
+
+impl Display for ChainPolicy {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ChainPolicy::FirstFound => write!(f, "first-found"),
+      ChainPolicy::Fallback => write!(f, "fallback"),
+      ChainPolicy::Override => write!(f, "override"),
+    }
+  }
+}
+
+/// Get configs from an ordered list of providers according to the [`ChainPolicy`].
+#[derive(Clone, Debug)]
+pub struct ChainConfigProvider {
+  /// Wrapped in an Arc to make [`Self`] cheap to clone.
+  providers: Arc<[AnyConfigProvider]>,
+  policy: ChainPolicy,
+}
+
+impl ChainConfigProvider {
+  pub fn new(providers: Vec<AnyConfigProvider>, policy: ChainPolicy) -> Self {
+    ChainConfigProvider {
+      providers: providers.into(),
+      policy,
+    }
+  }
+}
+
+impl ConfigProvider for ChainConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let mut error = None;
+    for cp in self.providers.iter() {
+      let e = match cp.clone().get(data_id, group, tenant, tag, refresh).await {
+        Ok(config) => return Ok(config),
+        Err(e) => e,
+      };
+      match (self.policy, e) {
+        (ChainPolicy::Fallback, ProviderError::NotFound) => return Err(ProviderError::NotFound),
+        (ChainPolicy::Override, e @ ProviderError::NotFound) | (ChainPolicy::FirstFound, e) => {
+          if !matches!(e, ProviderError::NotFound) {
+            warn!(provider = cp.name(), data_id, group, error = %e, "provider failed, try the next one");
+            error.get_or_insert(e);
+          }
+        }
+        (ChainPolicy::Override, e) => return Err(e),
+        (ChainPolicy::Fallback, e) => {
+          warn!(provider = cp.name(), data_id, group, error = %e, "provider failed, try the next one");
+          error = Some(e);
+        }
+      }
+    }
+    Err(error.unwrap_or(ProviderError::NotFound))
+  }
+
+  /// Changes are notified together if any provider asks for it.
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    let mut batched = false;
+    for cp in self.providers.iter() {
+      batched |= cp.begin_refresh(targets).await;
+    }
+    batched
+  }
+
+  async fn end_refresh(&self) {
+    for cp in self.providers.iter() {
+      cp.end_refresh().await;
+    }
+  }
+
+  /// Published by the first provider which supports writes.
+  async fn publish(
+    &self,
+    target: &Target,
+    content: String,
+    content_type: Option<&str>,
+    cas_md5: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    for cp in self.providers.iter() {
+      match cp
+        .publish(target, content.clone(), content_type, cas_md5)
+        .await
+      {
+        Err(ProviderError::Unsupported) => continue,
+        result => return result,
+      }
+    }
+    Err(ProviderError::Unsupported)
+  }
+
+  /// Removed by the first provider which supports writes.
+  async fn remove(&self, target: &Target) -> Result<(), ProviderError> {
+    for cp in self.providers.iter() {
+      match cp.remove(target).await {
+        Err(ProviderError::Unsupported) => continue,
+        result => return result,
+      }
+    }
+    Err(ProviderError::Unsupported)
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self.providers.iter().flat_map(|cp| cp.snapshot()).collect()
+  }
+
+  /// Each provider skips the entries not dumped by itself.
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    for cp in self.providers.iter() {
+      cp.restore(entries.clone()).await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::fs::FsConfigProvider;
+  use std::{fs, path::PathBuf};
+
+  /// A fs provider with the files under `public/g/`, a `None` content makes a directory,
+  /// which fails to be read.
+  fn fs_provider(dir: &str, files: &[(&str, Option<&str>)]) -> (AnyConfigProvider, PathBuf) {
+    let root = std::env::temp_dir().join(format!("chain-test-{}-{}", std::process::id(), dir));
+    let group = root.join("public/g");
+    fs::create_dir_all(&group).unwrap();
+    for (data_id, content) in files {
+      match content {
+        Some(content) => fs::write(group.join(data_id), content).unwrap(),
+        None => fs::create_dir_all(group.join(data_id)).unwrap(),
+      }
+    }
+    let prefix = format!("{}/", root.display());
+    (
+      AnyConfigProvider::Fs(FsConfigProvider::new(16, prefix)),
+      root,
+    )
+  }
+
+  /// The content of the config, or the error.
+  async fn get(chain: &ChainConfigProvider, data_id: &str) -> Result<String, String> {
+    match chain.clone().get(data_id, "g", None, None, false).await {
+      Ok(config) => Ok(config.content().to_string()),
+      Err(ProviderError::NotFound) => Err("not found".to_string()),
+      Err(ProviderError::Io(_)) => Err("io".to_string()),
+      Err(e) => Err(e.to_string()),
+    }
+  }
+
+  /// Run the test with a chain of two layers:
+  /// `a` is in both, `b` is only in the second one, and `c` fails in the first one.
+  async fn with_chain<F: std::future::Future<Output = ()>>(
+    name: &str,
+    policy: ChainPolicy,
+    test: impl FnOnce(ChainConfigProvider) -> F,
+  ) {
+    let (first, first_root) = fs_provider(
+      &format!("{}-first", name),
+      &[("a", Some("first a")), ("c", None)],
+    );
+    let (second, second_root) = fs_provider(
+      &format!("{}-second", name),
+      &[
+        ("a", Some("second a")),
+        ("b", Some("second b")),
+        ("c", Some("second c")),
+      ],
+    );
+    test(ChainConfigProvider::new(vec![first, second], policy)).await;
+    fs::remove_dir_all(first_root).unwrap();
+    fs::remove_dir_all(second_root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn first_found() {
+    with_chain("first-found", ChainPolicy::FirstFound, |chain| async move {
+      assert_eq!(get(&chain, "a").await.unwrap(), "first a");
+      assert_eq!(get(&chain, "b").await.unwrap(), "second b");
+      // the failing provider is skipped
+      assert_eq!(get(&chain, "c").await.unwrap(), "second c");
+      assert_eq!(get(&chain, "d").await.unwrap_err(), "not found");
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn first_found_returns_the_first_error() {
+    let (first, root) = fs_provider("first-error", &[("c", None)]);
+    let chain = ChainConfigProvider::new(vec![first], ChainPolicy::FirstFound);
+    assert_eq!(get(&chain, "c").await.unwrap_err(), "io");
+    fs::remove_dir_all(root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn fallback() {
+    with_chain("fallback", ChainPolicy::Fallback, |chain| async move {
+      assert_eq!(get(&chain, "a").await.unwrap(), "first a");
+      // not found in the primary provider is final
+      assert_eq!(get(&chain, "b").await.unwrap_err(), "not found");
+      assert_eq!(get(&chain, "c").await.unwrap(), "second c");
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn override_layers() {
+    with_chain("override", ChainPolicy::Override, |chain| async move {
+      assert_eq!(get(&chain, "a").await.unwrap(), "first a");
+      assert_eq!(get(&chain, "b").await.unwrap(), "second b");
+      // a failing layer is never replaced by the layer below it
+      assert_eq!(get(&chain, "c").await.unwrap_err(), "io");
+      assert_eq!(get(&chain, "d").await.unwrap_err(), "not found");
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn writes_are_unsupported_without_passthrough() {
+    with_chain("writes", ChainPolicy::FirstFound, |chain| async move {
+      assert!(!chain
+        .providers
+        .iter()
+        .any(AnyConfigProvider::supports_writes));
+      let target = Target {
+        data_id: Arc::new("a".to_string()),
+        group: Arc::new("g".to_string()),
+        tenant: None,
+        tag: None,
+      };
+      assert!(matches!(
+        chain.publish(&target, "new".to_string(), None, None).await,
+        Err(ProviderError::Unsupported)
+      ));
+      assert!(matches!(
+        chain.remove(&target).await,
+        Err(ProviderError::Unsupported)
+      ));
+    })
+    .await;
+  }
+
+  #[test]
+  fn chain_policy_from_str() {
+    for policy in [
+      ChainPolicy::FirstFound,
+      ChainPolicy::Fallback,
+      ChainPolicy::Override,
+    ] {
+      assert_eq!(policy.to_string().parse(), Ok(policy));
+    }
+    assert!("First-Found".parse::<ChainPolicy>().is_err());
+    assert!("".parse::<ChainPolicy>().is_err());
+  }
+}
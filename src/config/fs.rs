@@ -1,14 +1,119 @@
-use super::{provider::ConfigProvider, Config};
-use lambda_extension::Error;
+pub mod watch;
+
+use super::{
+  content_type_of, persist::CacheEntry, provider::ConfigProvider, target::Target, Config,
+};
+use crate::error::ProviderError;
+use lambda_extension::tracing::{debug, warn};
 use moka::future::Cache;
-use std::{os::unix::fs::MetadataExt, sync::Arc};
+use std::{
+  fmt::Display,
+  fs::Metadata,
+  os::unix::fs::MetadataExt,
+  path::Path,
+  str::FromStr,
+  sync::{Arc, RwLock},
+};
 use tokio::fs;
 
+/// How to decide whether a cached file is changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeDetection {
+  /// Compare the mtime in nanoseconds.
+  Mtime,
+  /// Compare the mtime, the size and the inode, so replaced files are detected too.
+  Metadata,
+  /// Like [`Self::Metadata`], but if the metadata changes,
+  /// the file is only treated as changed if the content hash changes.
+  Content,
+}
+
+impl FromStr for ChangeDetection {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "mtime" => Ok(ChangeDetection::Mtime),
+      "metadata" => Ok(ChangeDetection::Metadata),
+      "content" => Ok(ChangeDetection::Content),
+      _ => Err(format!("unknown change detection: {}", s)),
+    }
+  }
+}
+
+impl Display for ChangeDetection {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ChangeDetection::Mtime => write!(f, "mtime"),
+      ChangeDetection::Metadata => write!(f, "metadata"),
+      ChangeDetection::Content => write!(f, "content"),
+    }
+  }
+}
+
+/// The version of a file, compared to decide whether the cached content is stale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileVersion {
+  /// In nanoseconds.
+  mtime: i64,
+  /// `0` if not compared.
+  size: u64,
+  /// `0` if not compared.
+  ino: u64,
+}
+
+impl FileVersion {
+  pub(crate) fn of(metadata: &Metadata, detection: ChangeDetection) -> Self {
+    let mtime = metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec();
+    match detection {
+      ChangeDetection::Mtime => FileVersion {
+        mtime,
+        size: 0,
+        ino: 0,
+      },
+      ChangeDetection::Metadata | ChangeDetection::Content => FileVersion {
+        mtime,
+        size: metadata.size(),
+        ino: metadata.ino(),
+      },
+    }
+  }
+
+  /// In milliseconds.
+  fn last_modified(&self) -> i64 {
+    self.mtime / 1_000_000
+  }
+}
+
+/// Persisted as `"{mtime}:{size}:{ino}"`.
+impl Display for FileVersion {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}:{}:{}", self.mtime, self.size, self.ino)
+  }
+}
+
+impl FromStr for FileVersion {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid file version: {}", s);
+    let parts: Vec<_> = s.split(':').collect();
+    let [mtime, size, ino] = parts[..] else {
+      return Err(invalid());
+    };
+    Ok(FileVersion {
+      mtime: mtime.parse().map_err(|_| invalid())?,
+      size: size.parse().map_err(|_| invalid())?,
+      ino: ino.parse().map_err(|_| invalid())?,
+    })
+  }
+}
+
 /// This is cheap to clone.
 #[derive(Clone, Debug)]
-pub struct CacheValue {
-  pub mtime: i64,
-  pub config: Arc<Config>,
+struct CacheValue {
+  version: FileVersion,
+  config: Arc<Config>,
 }
 
 #[derive(Clone, Debug)]
@@ -17,6 +122,11 @@ pub struct FsConfigProvider {
   cache: Cache<String, CacheValue>,
   /// Wrapped in an Arc to make [`Self`] cheap to clone.
   prefix: Arc<String>,
+  detection: ChangeDetection,
+  /// In the versioned layout, the prefix is a symlink to an immutable release directory,
+  /// this is the resolved directory with a trailing slash, which only changes when refreshing.
+  /// `None` if the layout is not versioned.
+  release: Option<Arc<RwLock<Arc<String>>>>,
 }
 
 impl FsConfigProvider {
@@ -24,59 +134,221 @@ impl FsConfigProvider {
     FsConfigProvider {
       cache: Cache::new(size),
       prefix: Arc::new(prefix),
+      detection: ChangeDetection::Metadata,
+      release: None,
+    }
+  }
+
+  pub fn with_change_detection(mut self, detection: ChangeDetection) -> Self {
+    self.detection = detection;
+    self
+  }
+
+  /// Use the versioned layout, where the prefix is a symlink (e.g. `current`) to the directory of a release.
+  /// The symlink is resolved once per refresh, so all targets in a refresh come from the same release.
+  pub fn with_versioned(mut self, versioned: bool) -> Self {
+    self.release = versioned.then(|| {
+      let release = match std::fs::canonicalize(self.prefix.as_str()) {
+        Ok(path) => release_dir(&path),
+        Err(e) => {
+          warn!(prefix = %self.prefix, error = %e, "failed to resolve the release");
+          self.prefix.to_string()
+        }
+      };
+      debug!(prefix = %self.prefix, release, "release resolved");
+      Arc::new(RwLock::new(Arc::new(release)))
+    });
+    self
+  }
+
+  /// The directory to read configs from.
+  fn root(&self) -> Arc<String> {
+    match &self.release {
+      Some(release) => release.read().unwrap().clone(),
+      None => self.prefix.clone(),
     }
   }
 }
 
+/// Return the resolved release directory with a trailing slash, like the prefix.
+fn release_dir(path: &Path) -> String {
+  format!("{}/", path.to_string_lossy().trim_end_matches('/'))
+}
+
 impl ConfigProvider for FsConfigProvider {
   async fn get(
     &mut self,
     data_id: &str,
     group: &str,
     tenant: Option<&str>,
+    tag: Option<&str>,
     refresh: bool,
-  ) -> Result<Arc<Config>, Error> {
-    let path = format!(
-      "{}{}/{}/{}",
-      self.prefix,
-      tenant.unwrap_or("public"),
-      group,
-      data_id
-    );
+  ) -> Result<Arc<Config>, ProviderError> {
+    let root = self.root();
+    let path = match tag {
+      Some(tag) => format!(
+        "{}{}/{}/tags/{}/{}",
+        root,
+        tenant.unwrap_or("public"),
+        group,
+        tag,
+        data_id
+      ),
+      None => format!(
+        "{}{}/{}/{}",
+        root,
+        tenant.unwrap_or("public"),
+        group,
+        data_id
+      ),
+    };
 
-    let mtime = if !refresh {
+    let cached = self.cache.get(&path).await;
+    let metadata = if !refresh {
       // if not refresh and value in cache, return it
-      if let Some(value) = self.cache.get(&path).await {
+      if let Some(value) = cached {
         return Ok(value.config);
       }
-      // not in cache, get mtime
-      fs::metadata(&path).await?.mtime()
+      // not in cache, get metadata
+      fs::metadata(&path).await?
     } else {
-      // check cache by mtime
-      let mtime = fs::metadata(&path).await?.mtime();
-      if let Some(value) = self.cache.get(&path).await {
-        if value.mtime == mtime {
-          // mtime match, cache hit
-          return Ok(value.config);
+      match fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+          // the file might be deleted, don't serve the cached content anymore
+          self.cache.invalidate(&path).await;
+          return Err(e.into());
         }
       }
-      // else, not in cache or mtime mismatch, forward mtime
-      mtime
     };
 
-    // read new content
+    // check cache by version, so unchanged files are never read again
+    let version = FileVersion::of(&metadata, self.detection);
+    if let Some(value) = &cached {
+      if value.version == version {
+        return Ok(value.config.clone());
+      }
+    }
+
+    // not in cache or version mismatch, read new content
     let content = fs::read_to_string(&path).await?;
-    let config = Arc::new(Config::new(content));
+    let config = match cached {
+      // the file is touched but the content is the same, keep the cached config
+      Some(value)
+        if self.detection == ChangeDetection::Content
+          && value.config.md5() == format!("{:x}", md5::compute(&content)) =>
+      {
+        value.config
+      }
+      _ => Arc::new(
+        Config::new(content)
+          .with_content_type(content_type_of(data_id))
+          .with_last_modified(version.last_modified()),
+      ),
+    };
     self
       .cache
       .insert(
         path,
         CacheValue {
-          mtime,
+          version,
           config: config.clone(),
         },
       )
       .await;
     Ok(config)
   }
+
+  /// Changes are notified together in the versioned layout, since they come from the same release.
+  async fn begin_refresh(&self, _targets: &[Target]) -> bool {
+    let Some(release) = &self.release else {
+      return false;
+    };
+    // a failed resolution keeps the current release
+    match fs::canonicalize(self.prefix.as_str()).await {
+      Ok(path) => {
+        let resolved = release_dir(&path);
+        let mut release = release.write().unwrap();
+        if release.as_str() != resolved {
+          debug!(from = %release, to = resolved, "release switched");
+          *release = Arc::new(resolved);
+        }
+      }
+      Err(e) => warn!(prefix = %self.prefix, error = %e, "failed to resolve the release"),
+    }
+    true
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self
+      .cache
+      .iter()
+      .map(|(path, value)| CacheEntry {
+        key: path.as_ref().clone(),
+        config: value.config,
+        version: Some(value.version.to_string()),
+      })
+      .collect()
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    for entry in entries {
+      // entries without a file version are not dumped by this provider
+      let Some(Ok(version)) = entry.version.as_deref().map(FileVersion::from_str) else {
+        continue;
+      };
+      // the cache is checked by version when refreshing, so a stale entry will be replaced
+      self
+        .cache
+        .insert(
+          entry.key,
+          CacheValue {
+            version,
+            config: entry.config,
+          },
+        )
+        .await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn file_version_round_trip() {
+    let version = FileVersion {
+      mtime: 1_700_000_000_123_456_789,
+      size: 42,
+      ino: 7,
+    };
+    assert_eq!(version.to_string(), "1700000000123456789:42:7");
+    assert_eq!(FileVersion::from_str(&version.to_string()), Ok(version));
+    assert_eq!(version.last_modified(), 1_700_000_000_123);
+  }
+
+  #[test]
+  fn file_version_malformed() {
+    for s in [
+      "", "1:2", "1:2:3:4", "a:2:3", "1::3", "1:-2:3", " 1:2:3", "1.5:2:3",
+    ] {
+      assert!(FileVersion::from_str(s).is_err(), "{:?}", s);
+    }
+  }
+
+  #[test]
+  fn file_version_of_metadata() {
+    let metadata = std::fs::metadata("Cargo.toml").unwrap();
+    let version = FileVersion::of(&metadata, ChangeDetection::Metadata);
+    assert_eq!(version.size, metadata.size());
+    assert_eq!(version.ino, metadata.ino());
+    assert_eq!(
+      version.mtime,
+      metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec()
+    );
+    // only the mtime is compared
+    let version = FileVersion::of(&metadata, ChangeDetection::Mtime);
+    assert_eq!((version.size, version.ino), (0, 0));
+  }
 }
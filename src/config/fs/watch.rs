@@ -0,0 +1,176 @@
+//! Watch the config directory with inotify and refresh changed targets immediately,
+//! so listeners are notified without waiting for an invocation.
+
+use crate::config::target::{Target, TargetMessage};
+use lambda_extension::tracing::{debug, warn};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+use tokio::{sync::mpsc, time::sleep};
+
+/// Inotify doesn't report changes made by other hosts on these file systems.
+const NETWORK_FS: &[&str] = &["nfs", "nfs4", "cifs", "smb3", "smbfs"];
+
+/// Watch `prefix` and send changed targets to the target manager,
+/// changes within `debounce` after the first one are sent together.
+/// If the prefix is on a network file system (e.g. EFS) or the watcher fails to start,
+/// changes are only noticed by the refresh of each invocation.
+pub fn spawn_watcher(prefix: &str, debounce: Duration, target_tx: mpsc::Sender<TargetMessage>) {
+  if let Some(fs_type) = network_fs_of(Path::new(prefix)) {
+    debug!(prefix, fs_type, "network file system, skip watching");
+    return;
+  }
+
+  let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+  let watcher = {
+    let prefix = prefix.to_string();
+    notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+      Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+        for target in event
+          .paths
+          .iter()
+          .filter_map(|path| target_of(&prefix, path))
+        {
+          // it's ok if the receiver is dropped
+          let _ = changed_tx.send(target);
+        }
+      }
+      Ok(_) => {}
+      Err(e) => warn!(error = %e, "watcher error"),
+    })
+  };
+  let mut watcher = match watcher {
+    Ok(watcher) => watcher,
+    Err(e) => {
+      warn!(prefix, error = %e, "failed to create watcher, skip watching");
+      return;
+    }
+  };
+  if let Err(e) = watcher.watch(Path::new(prefix), RecursiveMode::Recursive) {
+    warn!(prefix, error = %e, "failed to watch, skip watching");
+    return;
+  }
+  debug!(prefix, "watching");
+
+  tokio::spawn(async move {
+    // the watcher stops when dropped
+    let _watcher = watcher;
+    while let Some(first) = changed_rx.recv().await {
+      let mut changed = vec![first];
+      let debounce = sleep(debounce);
+      tokio::pin!(debounce);
+      loop {
+        tokio::select! {
+          _ = &mut debounce => break,
+          target = changed_rx.recv() => match target {
+            Some(target) => if !changed.contains(&target) {
+              changed.push(target);
+            }
+            None => break,
+          }
+        }
+      }
+
+      debug!(?changed, "files changed");
+      if target_tx
+        .send(TargetMessage::Refresh(changed))
+        .await
+        .is_err()
+      {
+        break;
+      }
+    }
+  });
+}
+
+/// Map a path to the target like [`super::FsConfigProvider`] does in reverse.
+fn target_of(prefix: &str, path: &Path) -> Option<Target> {
+  let parts: Vec<_> = path.to_str()?.strip_prefix(prefix)?.split('/').collect();
+  let (tenant, group, tag, data_id) = match parts[..] {
+    [tenant, group, data_id] => (tenant, group, None, data_id),
+    [tenant, group, "tags", tag, data_id] => (tenant, group, Some(tag), data_id),
+    _ => return None,
+  };
+  if [tenant, group, data_id]
+    .iter()
+    .chain(&tag)
+    .any(|s| s.is_empty())
+  {
+    return None;
+  }
+  Some(Target {
+    data_id: Arc::new(data_id.to_string()),
+    group: Arc::new(group.to_string()),
+    tenant: (tenant != "public").then(|| Arc::new(tenant.to_string())),
+    tag: tag.map(|t| Arc::new(t.to_string())),
+  })
+}
+
+/// Return the type of the file system `path` is on, if it's a network file system.
+fn network_fs_of(path: &Path) -> Option<String> {
+  let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+  let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+  // the longest mount point containing the path is the one it's on
+  let (_, fs_type) = mounts
+    .lines()
+    .filter_map(|line| {
+      let mut fields = line.split_whitespace();
+      let mount_point = fields.nth(1)?;
+      let fs_type = fields.next()?;
+      // spaces in the mount point are escaped as `\040`
+      Some((PathBuf::from(mount_point.replace("\\040", " ")), fs_type))
+    })
+    .filter(|(mount_point, _)| path.starts_with(mount_point))
+    .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())?;
+  NETWORK_FS.contains(&fs_type).then(|| fs_type.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(path: &str) -> Option<Target> {
+    target_of("/mnt/efs/nacos/", Path::new(path))
+  }
+
+  #[test]
+  fn target_of_config() {
+    let target = parse("/mnt/efs/nacos/public/DEFAULT_GROUP/app.yaml").unwrap();
+    assert_eq!(target.data_id.as_str(), "app.yaml");
+    assert_eq!(target.group.as_str(), "DEFAULT_GROUP");
+    // the public namespace is the default tenant
+    assert_eq!(target.tenant(), None);
+    assert_eq!(target.tag(), None);
+
+    let target = parse("/mnt/efs/nacos/dev/g/app").unwrap();
+    assert_eq!(target.tenant(), Some("dev"));
+  }
+
+  #[test]
+  fn target_of_tagged_config() {
+    let target = parse("/mnt/efs/nacos/dev/g/tags/canary/app").unwrap();
+    assert_eq!(target.data_id.as_str(), "app");
+    assert_eq!(target.group.as_str(), "g");
+    assert_eq!(target.tenant(), Some("dev"));
+    assert_eq!(target.tag(), Some("canary"));
+  }
+
+  #[test]
+  fn target_of_other_paths() {
+    // outside the prefix
+    assert!(parse("/tmp/public/g/app").is_none());
+    // directories
+    assert!(parse("/mnt/efs/nacos/public").is_none());
+    assert!(parse("/mnt/efs/nacos/public/g").is_none());
+    assert!(parse("/mnt/efs/nacos/public/g/tags/canary").is_none());
+    // too deep
+    assert!(parse("/mnt/efs/nacos/public/g/sub/app").is_none());
+    // empty components
+    assert!(parse("/mnt/efs/nacos/public//app").is_none());
+    assert!(parse("/mnt/efs/nacos/public/g/tags//app").is_none());
+  }
+}
@@ -0,0 +1,328 @@
+use super::{content_type_of, provider::ConfigProvider, target::Target, Config};
+use crate::error::ProviderError;
+use lambda_extension::tracing::{debug, warn};
+use moka::future::Cache;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex, RwLock},
+  time::Duration,
+};
+use tokio::{process::Command, time::Instant};
+
+/// The commit of the ref.
+#[derive(Clone, Debug)]
+struct Revision {
+  commit: Arc<String>,
+  /// The commit time in milliseconds.
+  time: i64,
+}
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+struct CacheValue {
+  /// The id of the blob, the config is only read again if it changes.
+  blob: Arc<String>,
+  config: Arc<Config>,
+}
+
+/// The blobs listed at the beginning of the current refresh.
+#[derive(Debug, Default)]
+struct Listing {
+  commit: Arc<String>,
+  /// Keyed by the path, `None` if the path doesn't exist in the commit.
+  blobs: HashMap<String, Option<Arc<String>>>,
+}
+
+/// Read configs from a local git repository (bare or not) at a ref,
+/// where the config of a target is the file `{tenant}/{group}/{dataId}`,
+/// or `{tenant}/{group}/tags/{tag}/{dataId}` if tagged.
+/// The version of configs is the commit they are read from.
+#[derive(Clone, Debug)]
+pub struct GitConfigProvider {
+  /// Moka cache keyed by the path, which is cheap to clone.
+  cache: Cache<String, CacheValue>,
+  /// The path of the repository.
+  repo: Arc<String>,
+  /// The ref to read, e.g. `main`.
+  reference: Arc<String>,
+  /// The remote to fetch the ref from when refreshing, the ref must be a branch then.
+  /// `None` if the repository is never fetched.
+  remote: Option<Arc<String>>,
+  /// Fetching is killed after this, then the local branch is kept.
+  fetch_timeout: Option<Duration>,
+  /// Fetch at most once in this interval, refreshes in between keep the local branch.
+  fetch_interval: Duration,
+  /// When the last fetch started, `None` if never fetched.
+  last_fetch: Arc<Mutex<Option<Instant>>>,
+  /// The revision resolved at the beginning of the current refresh, `None` if not resolved yet.
+  revision: Arc<RwLock<Option<Revision>>>,
+  listing: Arc<RwLock<Listing>>,
+}
+
+impl GitConfigProvider {
+  pub fn new(size: u64, repo: String, reference: String, remote: Option<String>) -> Self {
+    GitConfigProvider {
+      cache: Cache::new(size),
+      repo: Arc::new(repo),
+      reference: Arc::new(reference),
+      remote: remote.map(Arc::new),
+      fetch_timeout: Some(Duration::from_secs(5)),
+      fetch_interval: Duration::ZERO,
+      last_fetch: Default::default(),
+      revision: Default::default(),
+      listing: Default::default(),
+    }
+  }
+
+  pub fn with_fetch_timeout(mut self, timeout: Option<Duration>) -> Self {
+    self.fetch_timeout = timeout;
+    self
+  }
+
+  pub fn with_fetch_interval(mut self, interval: Duration) -> Self {
+    self.fetch_interval = interval;
+    self
+  }
+
+  /// Run git in the repository and return the stdout.
+  async fn git(&self, args: &[&str]) -> Result<Vec<u8>, ProviderError> {
+    let output = Command::new("git")
+      .arg("-C")
+      .arg(self.repo.as_str())
+      .args(args)
+      // never wait for credentials, and treat paths literally instead of as patterns
+      .env("GIT_TERMINAL_PROMPT", "0")
+      .env("GIT_LITERAL_PATHSPECS", "1")
+      .kill_on_drop(true)
+      .output()
+      .await
+      .map_err(|e| ProviderError::Upstream(format!("failed to run git: {}", e)))?;
+    if !output.status.success() {
+      return Err(ProviderError::Upstream(format!(
+        "git {} failed: {}",
+        args[0],
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    Ok(output.stdout)
+  }
+
+  /// Fetch the branch from the remote and fast-forward the local branch,
+  /// unless it was fetched within the interval.
+  async fn fetch(&self, remote: &str) -> Result<(), ProviderError> {
+    {
+      let mut last_fetch = self.last_fetch.lock().unwrap();
+      if last_fetch.is_some_and(|t| t.elapsed() < self.fetch_interval) {
+        return Ok(());
+      }
+      // a failed fetch is throttled too, so a broken remote is not hammered
+      *last_fetch = Some(Instant::now());
+    }
+
+    // without a leading `+`, git refuses to update the branch if it's not a fast-forward
+    let refspec = format!("refs/heads/{0}:refs/heads/{0}", self.reference);
+    let args = ["fetch", "--quiet", "--update-head-ok", remote, &refspec];
+    let result = match self.fetch_timeout {
+      // git is killed if it times out
+      Some(timeout) => tokio::time::timeout(timeout, self.git(&args))
+        .await
+        .map_err(|_| ProviderError::Timeout)?,
+      None => self.git(&args).await,
+    };
+    // the error might contain the url of the remote with credentials
+    result.map_err(|_| ProviderError::Upstream(format!("failed to fetch {}", refspec)))?;
+    Ok(())
+  }
+
+  async fn resolve(&self) -> Result<Revision, ProviderError> {
+    let rev = format!("{}^{{commit}}", self.reference);
+    let output = self
+      .git(&["show", "--no-patch", "--format=%H %ct", &rev, "--"])
+      .await?;
+    let output = String::from_utf8_lossy(&output);
+    let Some((commit, time)) = output.trim().split_once(' ') else {
+      return Err(ProviderError::Upstream(format!(
+        "failed to resolve {}",
+        self.reference
+      )));
+    };
+    Ok(Revision {
+      commit: Arc::new(commit.to_string()),
+      time: time.parse::<i64>().unwrap_or_default() * 1000,
+    })
+  }
+
+  /// Return the revision of the current refresh, or resolve it if not resolved yet.
+  async fn revision(&self) -> Result<Revision, ProviderError> {
+    if let Some(revision) = self.revision.read().unwrap().clone() {
+      return Ok(revision);
+    }
+    let revision = self.resolve().await?;
+    *self.revision.write().unwrap() = Some(revision.clone());
+    Ok(revision)
+  }
+
+  /// List the blobs of the paths in the commit.
+  async fn list(
+    &self,
+    commit: &str,
+    paths: &[&str],
+  ) -> Result<HashMap<String, Option<Arc<String>>>, ProviderError> {
+    let mut args = vec!["ls-tree", "-z", commit, "--"];
+    args.extend(paths);
+    let output = self.git(&args).await?;
+
+    let mut blobs: HashMap<_, _> = paths.iter().map(|path| (path.to_string(), None)).collect();
+    // each entry is `{mode} {type} {object}\t{path}`
+    for entry in output.split(|b| *b == 0).filter(|e| !e.is_empty()) {
+      let entry = String::from_utf8_lossy(entry);
+      let Some((meta, path)) = entry.split_once('\t') else {
+        continue;
+      };
+      if let [_, "blob", blob] = meta.split(' ').collect::<Vec<_>>()[..] {
+        blobs.insert(path.to_string(), Some(Arc::new(blob.to_string())));
+      }
+    }
+    Ok(blobs)
+  }
+
+  /// Return the blob of the path in the commit, listed at the beginning of the refresh if possible.
+  async fn blob_of(&self, commit: &Arc<String>, path: &str) -> Result<Arc<String>, ProviderError> {
+    let listed = {
+      let listing = self.listing.read().unwrap();
+      if listing.commit == *commit {
+        listing.blobs.get(path).cloned()
+      } else {
+        None
+      }
+    };
+    let blob = match listed {
+      Some(blob) => blob,
+      None => self.list(commit, &[path]).await?.remove(path).flatten(),
+    };
+    blob.ok_or(ProviderError::NotFound)
+  }
+}
+
+impl ConfigProvider for GitConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let path = path_of(data_id, group, tenant, tag);
+
+    let cached = self.cache.get(&path).await;
+    if !refresh {
+      if let Some(value) = cached {
+        return Ok(value.config);
+      }
+    }
+
+    let revision = self.revision().await?;
+    let blob = match self.blob_of(&revision.commit, &path).await {
+      Ok(blob) => blob,
+      Err(ProviderError::NotFound) => {
+        // the file might be deleted, don't serve the cached content anymore
+        self.cache.invalidate(&path).await;
+        return Err(ProviderError::NotFound);
+      }
+      Err(e) => return Err(e),
+    };
+
+    let config = match cached {
+      // the content is the same, only the version changes
+      Some(value) if value.blob == blob => {
+        if value.config.version() == Some(&revision.commit) {
+          return Ok(value.config);
+        }
+        Arc::new(Config::clone(&value.config).with_version(revision.commit.clone()))
+      }
+      _ => {
+        let content = self.git(&["cat-file", "blob", &blob]).await?;
+        Arc::new(
+          Config::new(String::from_utf8_lossy(&content).into_owned())
+            .with_content_type(content_type_of(data_id))
+            .with_last_modified(revision.time)
+            .with_version(revision.commit.clone()),
+        )
+      }
+    };
+    self
+      .cache
+      .insert(
+        path,
+        CacheValue {
+          blob,
+          config: config.clone(),
+        },
+      )
+      .await;
+    Ok(config)
+  }
+
+  /// Fetch and resolve the ref once per refresh, so all targets in a refresh come from the same commit,
+  /// then list the blobs of all targets at once.
+  /// Changes are notified together, since they come from the same commit.
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    if let Some(remote) = &self.remote {
+      // a failed fetch keeps the local branch
+      if let Err(e) = self.fetch(remote).await {
+        warn!(repo = %self.repo, error = %e, "failed to fetch");
+      }
+    }
+
+    let revision = match self.resolve().await {
+      Ok(revision) => revision,
+      Err(e) => {
+        // a failed resolution keeps the current revision
+        warn!(repo = %self.repo, error = %e, "failed to resolve the ref");
+        return true;
+      }
+    };
+    {
+      let mut current = self.revision.write().unwrap();
+      let from = current.as_ref().map(|r| r.commit.clone());
+      if from.as_ref() != Some(&revision.commit) {
+        debug!(?from, to = %revision.commit, "commit switched");
+        *current = Some(revision.clone());
+      }
+    }
+
+    let paths: Vec<_> = targets
+      .iter()
+      .map(|t| path_of(&t.data_id, &t.group, t.tenant(), t.tag()))
+      .collect();
+    let paths: Vec<_> = paths.iter().map(|p| p.as_str()).collect();
+    if paths.is_empty() {
+      return true;
+    }
+    match self.list(&revision.commit, &paths).await {
+      Ok(blobs) => {
+        *self.listing.write().unwrap() = Listing {
+          commit: revision.commit,
+          blobs,
+        }
+      }
+      // targets will be listed one by one
+      Err(e) => warn!(repo = %self.repo, error = %e, "failed to list blobs"),
+    }
+    true
+  }
+}
+
+fn path_of(data_id: &str, group: &str, tenant: Option<&str>, tag: Option<&str>) -> String {
+  match tag {
+    Some(tag) => format!(
+      "{}/{}/tags/{}/{}",
+      tenant.unwrap_or("public"),
+      group,
+      tag,
+      data_id
+    ),
+    None => format!("{}/{}/{}", tenant.unwrap_or("public"), group, data_id),
+  }
+}
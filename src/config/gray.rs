@@ -0,0 +1,264 @@
+use super::{persist::CacheEntry, provider::ConfigProvider, target::Target, Config};
+use crate::error::ProviderError;
+use lambda_extension::tracing::{debug, warn};
+use serde::Deserialize;
+use std::{
+  collections::{hash_map::RandomState, BTreeSet, HashMap},
+  env,
+  hash::{BuildHasher, Hasher},
+  sync::{Arc, RwLock},
+};
+
+/// A gray rule selects a tagged variant of a config for matched sandboxes.
+/// All specified conditions must match.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rule {
+  data_id: String,
+  /// Match all groups if not specified.
+  group: Option<String>,
+  /// The tag of the variant.
+  tag: String,
+  /// Match `AWS_LAMBDA_FUNCTION_VERSION`.
+  version: Option<String>,
+  /// Match the alias in the invoked function ARN.
+  alias: Option<String>,
+  /// Match a stable percentage of sandboxes, from `0` to `100`.
+  percentage: Option<u32>,
+}
+
+/// Rules of a tenant in the order they are matched.
+type Rules = Arc<Vec<Rule>>;
+
+/// Attributes of this sandbox used to match gray rules.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+  version: Arc<String>,
+  /// Unknown until the first invocation.
+  alias: Arc<RwLock<Option<String>>>,
+  /// A stable number in `0..100` derived from the sandbox id.
+  bucket: u32,
+}
+
+impl Sandbox {
+  pub fn from_env() -> Self {
+    let version = env::var("AWS_LAMBDA_FUNCTION_VERSION").unwrap_or_else(|_| "$LATEST".to_string());
+    // the log stream name is unique for each sandbox,
+    // otherwise use a random id, so sandboxes are still spread over buckets
+    let id = env::var("AWS_LAMBDA_LOG_STREAM_NAME")
+      .unwrap_or_else(|_| format!("{:x}", RandomState::new().build_hasher().finish()));
+    let digest = md5::compute(&id);
+    let bucket = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100;
+    debug!(version, id, bucket, "sandbox");
+    Sandbox {
+      version: Arc::new(version),
+      alias: Default::default(),
+      bucket,
+    }
+  }
+
+  /// Update the alias from the invoked function ARN,
+  /// which is `arn:aws:lambda:{region}:{account}:function:{name}[:{version or alias}]`.
+  pub fn set_alias_from_arn(&self, arn: &str) {
+    let alias = arn
+      .split(':')
+      .nth(7)
+      .filter(|q| *q != "$LATEST" && !q.chars().all(|c| c.is_ascii_digit()))
+      .map(|q| q.to_string());
+    let mut current = self.alias.write().unwrap();
+    if *current != alias {
+      debug!(?alias, "alias changed");
+      *current = alias;
+    }
+  }
+
+  fn matches(&self, rule: &Rule) -> bool {
+    rule
+      .version
+      .as_ref()
+      .is_none_or(|v| v == self.version.as_str())
+      && rule
+        .alias
+        .as_ref()
+        .is_none_or(|a| self.alias.read().unwrap().as_ref() == Some(a))
+      && rule.percentage.is_none_or(|p| self.bucket < p)
+  }
+}
+
+/// Wrap a config provider and serve a tagged variant of an untagged config if a gray rule matches this sandbox.
+/// Rules are a JSON array stored as a config in the same tenant, fetched through the inner provider.
+#[derive(Clone, Debug)]
+pub struct GrayConfigProvider<CP> {
+  inner: CP,
+  /// `(data_id, group)` of the rules, `None` if gray release is disabled.
+  rules: Option<Arc<(String, String)>>,
+  /// Parsed rules keyed by the tenant, refreshed once per refresh instead of once per target.
+  /// Empty if the tenant has no rules.
+  parsed: Arc<RwLock<HashMap<Option<String>, Rules>>>,
+  sandbox: Sandbox,
+}
+
+impl<CP> GrayConfigProvider<CP> {
+  pub fn new(inner: CP, rules: Option<(String, String)>, sandbox: Sandbox) -> Self {
+    GrayConfigProvider {
+      inner,
+      rules: rules.map(Arc::new),
+      parsed: Default::default(),
+      sandbox,
+    }
+  }
+}
+
+impl<CP: ConfigProvider> GrayConfigProvider<CP> {
+  /// Read and parse the rules of the tenant through the inner provider.
+  /// Return `None` if they can't be read now, then the parsed rules are kept.
+  async fn load(&self, tenant: Option<&str>, refresh: bool) -> Option<Rules> {
+    let (rules_data_id, rules_group) = self.rules.as_deref()?;
+    let rules = match self
+      .inner
+      .clone()
+      .get(rules_data_id, rules_group, tenant, None, refresh)
+      .await
+    {
+      Ok(config) => match serde_json::from_str(config.content()) {
+        Ok(rules) => rules,
+        Err(e) => {
+          warn!(tenant, error = %e, "invalid gray rules, serve the default config");
+          vec![]
+        }
+      },
+      Err(ProviderError::NotFound) => vec![],
+      Err(e) => {
+        warn!(tenant, error = %e, "failed to get gray rules, serve the default config");
+        return None;
+      }
+    };
+    let rules = Arc::new(rules);
+    self
+      .parsed
+      .write()
+      .unwrap()
+      .insert(tenant.map(str::to_string), rules.clone());
+    Some(rules)
+  }
+
+  /// Return the tag of the first matched rule.
+  /// The rules are loaded on the first use, then refreshed once in [`ConfigProvider::begin_refresh`].
+  async fn select(&self, data_id: &str, group: &str, tenant: Option<&str>) -> Option<String> {
+    self.rules.as_ref()?;
+    let parsed = self
+      .parsed
+      .read()
+      .unwrap()
+      .get(&tenant.map(str::to_string))
+      .cloned();
+    let rules = match parsed {
+      Some(rules) => rules,
+      None => self.load(tenant, false).await?,
+    };
+    rules
+      .iter()
+      .filter(|rule| rule.data_id == data_id)
+      .filter(|rule| rule.group.as_ref().is_none_or(|g| g == group))
+      .find(|rule| self.sandbox.matches(rule))
+      .map(|rule| rule.tag.clone())
+  }
+}
+
+impl<CP: ConfigProvider> ConfigProvider for GrayConfigProvider<CP> {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    // explicitly tagged configs and the rules themselves are served as is
+    let is_rules = self
+      .rules
+      .as_ref()
+      .is_some_and(|rules| rules.0 == data_id && rules.1 == group);
+    if tag.is_some() || is_rules {
+      return self.inner.get(data_id, group, tenant, tag, refresh).await;
+    }
+
+    if let Some(tag) = self.select(data_id, group, tenant).await {
+      match self
+        .inner
+        .get(data_id, group, tenant, Some(&tag), refresh)
+        .await
+      {
+        Ok(config) => {
+          debug!(data_id, group, tenant, tag, "serve gray config");
+          return Ok(config);
+        }
+        Err(e) => {
+          warn!(data_id, group, tenant, tag, error = %e, "failed to get gray config, serve the default config")
+        }
+      }
+    }
+
+    self.inner.get(data_id, group, tenant, None, refresh).await
+  }
+
+  /// Refresh the rules of each tenant once, instead of once per target.
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    let Some(rules) = &self.rules else {
+      return self.inner.begin_refresh(targets).await;
+    };
+    // tenants whose rules are already loaded are refreshed too, they might be used by clients not listening
+    let mut tenants: BTreeSet<_> = targets.iter().map(|t| t.tenant.clone()).collect();
+    tenants.extend(
+      self
+        .parsed
+        .read()
+        .unwrap()
+        .keys()
+        .map(|tenant| tenant.clone().map(Arc::new)),
+    );
+    let mut targets = targets.to_vec();
+    targets.extend(tenants.iter().map(|tenant| Target {
+      data_id: Arc::new(rules.0.clone()),
+      group: Arc::new(rules.1.clone()),
+      tenant: tenant.clone(),
+      tag: None,
+    }));
+    let batched = self.inner.begin_refresh(&targets).await;
+
+    for tenant in tenants {
+      self.load(tenant.as_deref().map(|t| t.as_str()), true).await;
+    }
+    batched
+  }
+
+  async fn end_refresh(&self) {
+    self.inner.end_refresh().await
+  }
+
+  async fn publish(
+    &self,
+    target: &Target,
+    content: String,
+    content_type: Option<&str>,
+    cas_md5: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    self
+      .inner
+      .publish(target, content, content_type, cas_md5)
+      .await
+  }
+
+  async fn remove(&self, target: &Target) -> Result<(), ProviderError> {
+    self.inner.remove(target).await
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self.inner.snapshot()
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    self.inner.restore(entries).await
+  }
+}
@@ -0,0 +1,142 @@
+use super::{
+  content_type_of, md5_of, parse_content_type, persist::CacheEntry, provider::ConfigProvider,
+  target::Target, Config,
+};
+use crate::{error::ProviderError, naming::now_millis};
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+
+/// Wrap a config provider and keep configs published or removed by local clients,
+/// which take precedence over the inner provider and are only visible in this sandbox.
+#[derive(Clone, Debug)]
+pub struct LocalConfigProvider<CP> {
+  inner: CP,
+  /// `None` if the config is removed.
+  /// This is locked across the md5 check of a publish, so concurrent publishes with `casMd5` won't race.
+  overrides: Arc<Mutex<HashMap<Target, Option<Arc<Config>>>>>,
+  /// Forward writes to the inner provider instead of keeping them locally.
+  forward: bool,
+}
+
+impl<CP> LocalConfigProvider<CP> {
+  pub fn new(inner: CP, forward: bool) -> Self {
+    LocalConfigProvider {
+      inner,
+      overrides: Default::default(),
+      forward,
+    }
+  }
+}
+
+impl<CP: ConfigProvider> ConfigProvider for LocalConfigProvider<CP> {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    {
+      let overrides = self.overrides.lock().await;
+      if !overrides.is_empty() {
+        let target = Target {
+          data_id: Arc::new(data_id.to_string()),
+          group: Arc::new(group.to_string()),
+          tenant: tenant.map(|t| Arc::new(t.to_string())),
+          tag: tag.map(|t| Arc::new(t.to_string())),
+        };
+        if let Some(config) = overrides.get(&target) {
+          return config.clone().ok_or(ProviderError::NotFound);
+        }
+      }
+    }
+    self.inner.get(data_id, group, tenant, tag, refresh).await
+  }
+
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    self.inner.begin_refresh(targets).await
+  }
+
+  async fn end_refresh(&self) {
+    self.inner.end_refresh().await
+  }
+
+  async fn publish(
+    &self,
+    target: &Target,
+    content: String,
+    content_type: Option<&str>,
+    cas_md5: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    if self.forward {
+      let config = self
+        .inner
+        .publish(target, content, content_type, cas_md5)
+        .await?;
+      self.overrides.lock().await.remove(target);
+      return Ok(config);
+    }
+
+    let mut overrides = self.overrides.lock().await;
+    if let Some(cas_md5) = cas_md5 {
+      let current = match overrides.get(target) {
+        Some(config) => config.as_ref().map_or("", |c| c.md5()).to_owned(),
+        None => {
+          let result = self
+            .inner
+            .clone()
+            .get(
+              &target.data_id,
+              &target.group,
+              target.tenant(),
+              target.tag(),
+              false,
+            )
+            .await;
+          match md5_of(&result) {
+            Some(md5) => md5.to_owned(),
+            None => return Err(result.unwrap_err()),
+          }
+        }
+      };
+      if current != cas_md5 {
+        return Err(ProviderError::Conflict(format!(
+          "md5 mismatch, current md5 is {:?}",
+          current
+        )));
+      }
+    }
+
+    let content_type =
+      content_type.map_or_else(|| content_type_of(&target.data_id), parse_content_type);
+    let config = Arc::new(
+      Config::new(content)
+        .with_content_type(content_type)
+        .with_last_modified(now_millis()),
+    );
+    overrides.insert(target.clone(), Some(config.clone()));
+    Ok(config)
+  }
+
+  async fn remove(&self, target: &Target) -> Result<(), ProviderError> {
+    if self.forward {
+      self.inner.remove(target).await?;
+      self.overrides.lock().await.remove(target);
+      return Ok(());
+    }
+
+    // keep the removal, so the config of the inner provider is hidden too
+    self.overrides.lock().await.insert(target.clone(), None);
+    Ok(())
+  }
+
+  /// Local configs are not persisted, clients publish them again after a cold start.
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self.inner.snapshot()
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    self.inner.restore(entries).await
+  }
+}
@@ -1,22 +1,120 @@
-use super::{provider::ConfigProvider, Config};
-use lambda_extension::Error;
+use super::{
+  content_type_of, fs::FsConfigProvider, parse_content_type, persist::CacheEntry,
+  provider::ConfigProvider, target::Target, Config,
+};
+use crate::{error::ProviderError, naming::now_millis, origin::Origin};
+use lambda_extension::tracing::warn;
 use moka::future::Cache;
-use reqwest::Url;
-use std::sync::Arc;
+use reqwest::{Response, StatusCode};
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+};
+use tokio::time::Instant;
+
+/// The last good config of a key.
+#[derive(Clone, Debug)]
+struct StaleValue {
+  config: Arc<Config>,
+  fetched_at: Instant,
+}
 
 #[derive(Clone, Debug)]
 pub struct PassthroughConfigProvider {
   /// Moka cache, which is cheap to clone.
   cache: Cache<String, Arc<Config>>,
-  /// Wrapped in an Arc to make [`Self`] cheap to clone.
-  base: Arc<String>,
+  /// The origin nacos server, which is cheap to clone.
+  origin: Origin,
+  /// The last good config of every key, which is never evicted.
+  /// Served when the origin is unreachable.
+  /// `None` if stale-if-error is disabled.
+  stale: Option<Arc<Mutex<HashMap<String, StaleValue>>>>,
+  /// Served when the origin is unreachable and there is no stale config.
+  fallback: Option<FsConfigProvider>,
 }
 
 impl PassthroughConfigProvider {
-  pub fn new(size: u64, addr: String) -> Self {
+  pub fn new(
+    size: u64,
+    origin: Origin,
+    stale_if_error: bool,
+    fallback: Option<FsConfigProvider>,
+  ) -> Self {
     PassthroughConfigProvider {
       cache: Cache::new(size),
-      base: Arc::new(format!("http://{}/nacos/v1/cs/configs", addr)),
+      origin,
+      stale: stale_if_error.then(Default::default),
+      fallback,
+    }
+  }
+
+  async fn fetch(
+    &self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let mut params = vec![("dataId", data_id), ("group", group)];
+    if let Some(tenant) = tenant {
+      params.push(("tenant", tenant));
+    }
+    if let Some(tag) = tag {
+      params.push(("tag", tag));
+    }
+    let res = self.origin.get("/nacos/v1/cs/configs", &params).await?;
+    match res.status() {
+      StatusCode::OK => {
+        // nacos responds with this header if the config is a beta config
+        let beta = res
+          .headers()
+          .get("isBeta")
+          .is_some_and(|v| v.as_bytes() == b"true");
+        // detect the type from the data id if the origin doesn't tell
+        let content_type = res
+          .headers()
+          .get("Config-Type")
+          .and_then(|v| v.to_str().ok())
+          .map_or_else(|| content_type_of(data_id), parse_content_type);
+        // nacos responds with the last modified time in milliseconds
+        let last_modified = res
+          .headers()
+          .get("Last-Modified")
+          .and_then(|v| v.to_str().ok())
+          .and_then(|v| v.parse().ok())
+          .unwrap_or_default();
+        Ok(Arc::new(
+          Config::new(res.text().await?)
+            .with_beta(beta)
+            .with_content_type(content_type)
+            .with_last_modified(last_modified),
+        ))
+      }
+      _ => Err(error_of(res).await),
+    }
+  }
+
+  /// Replace the cached config of the key, e.g. after the config is published to the origin.
+  async fn update_cache(&self, key: String, config: Option<Arc<Config>>) {
+    match config {
+      Some(config) => {
+        if let Some(stale) = &self.stale {
+          stale.lock().unwrap().insert(
+            key.clone(),
+            StaleValue {
+              config: config.clone(),
+              fetched_at: Instant::now(),
+            },
+          );
+        }
+        self.cache.insert(key, config).await;
+      }
+      None => {
+        if let Some(stale) = &self.stale {
+          stale.lock().unwrap().remove(&key);
+        }
+        self.cache.invalidate(&key).await;
+      }
     }
   }
 }
@@ -27,9 +125,10 @@ impl ConfigProvider for PassthroughConfigProvider {
     data_id: &str,
     group: &str,
     tenant: Option<&str>,
+    tag: Option<&str>,
     refresh: bool,
-  ) -> Result<Arc<Config>, Error> {
-    let key = format!("{}/{}/{}", tenant.unwrap_or(""), group, data_id);
+  ) -> Result<Arc<Config>, ProviderError> {
+    let key = cache_key(data_id, group, tenant, tag);
 
     if !refresh {
       if let Some(value) = self.cache.get(&key).await {
@@ -37,22 +136,162 @@ impl ConfigProvider for PassthroughConfigProvider {
       }
     }
 
-    let content = reqwest::get({
-      if let Some(tenant) = tenant {
-        Url::parse_with_params(
-          &self.base,
-          [("dataId", data_id), ("group", group), ("tenant", tenant)],
-        )
-      } else {
-        Url::parse_with_params(&self.base, [("dataId", data_id), ("group", group)])
-      }?
-    })
-    .await?
-    .text()
-    .await?;
+    let err = match self.fetch(data_id, group, tenant, tag).await {
+      Ok(config) => {
+        self.update_cache(key, Some(config.clone())).await;
+        return Ok(config);
+      }
+      Err(ProviderError::NotFound) => {
+        // the config might be deleted, don't serve the cached content anymore
+        self.update_cache(key, None).await;
+        return Err(ProviderError::NotFound);
+      }
+      Err(err) => err,
+    };
 
-    let config = Arc::new(Config::new(content));
-    self.cache.insert(key, config.clone()).await;
+    // the origin is unreachable, try the last good config
+    let stale = self
+      .stale
+      .as_ref()
+      .and_then(|stale| stale.lock().unwrap().get(&key).cloned());
+    if let Some(stale) = stale {
+      warn!(
+        data_id,
+        group,
+        tenant,
+        error = %err,
+        age_ms = stale.fetched_at.elapsed().as_millis(),
+        "failed to fetch config from origin, serve stale config"
+      );
+      self.cache.insert(key, stale.config.clone()).await;
+      return Ok(stale.config);
+    }
+
+    // nothing was ever fetched, try the fallback
+    if let Some(fallback) = &mut self.fallback {
+      if let Ok(config) = fallback.get(data_id, group, tenant, tag, false).await {
+        warn!(
+          data_id,
+          group,
+          tenant,
+          error = %err,
+          "failed to fetch config from origin, serve fallback config"
+        );
+        return Ok(config);
+      }
+    }
+
+    Err(err)
+  }
+
+  async fn publish(
+    &self,
+    target: &Target,
+    content: String,
+    content_type: Option<&str>,
+    cas_md5: Option<&str>,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let mut params = vec![
+      ("dataId", target.data_id.as_str()),
+      ("group", target.group.as_str()),
+      ("content", &content),
+    ];
+    params.extend(target.tenant().map(|tenant| ("tenant", tenant)));
+    params.extend(target.tag().map(|tag| ("tag", tag)));
+    params.extend(content_type.map(|t| ("type", t)));
+    params.extend(cas_md5.map(|md5| ("casMd5", md5)));
+    let res = self.origin.post("/nacos/v1/cs/configs", &params).await?;
+    if res.status() != StatusCode::OK {
+      return Err(error_of(res).await);
+    }
+    // nacos responds with `false` if the md5 mismatches
+    if res.text().await?.trim() != "true" {
+      return Err(ProviderError::Conflict(
+        "origin refused to publish the config".to_string(),
+      ));
+    }
+
+    // the origin might not be consistent right after publishing, serve the published config from the cache
+    let content_type =
+      content_type.map_or_else(|| content_type_of(&target.data_id), parse_content_type);
+    let config = Arc::new(
+      Config::new(content)
+        .with_content_type(content_type)
+        .with_last_modified(now_millis()),
+    );
+    let key = cache_key(
+      &target.data_id,
+      &target.group,
+      target.tenant(),
+      target.tag(),
+    );
+    self.update_cache(key, Some(config.clone())).await;
     Ok(config)
   }
+
+  async fn remove(&self, target: &Target) -> Result<(), ProviderError> {
+    let mut params = vec![
+      ("dataId", target.data_id.as_str()),
+      ("group", target.group.as_str()),
+    ];
+    params.extend(target.tenant().map(|tenant| ("tenant", tenant)));
+    params.extend(target.tag().map(|tag| ("tag", tag)));
+    let res = self.origin.delete("/nacos/v1/cs/configs", &params).await?;
+    if res.status() != StatusCode::OK {
+      return Err(error_of(res).await);
+    }
+    let key = cache_key(
+      &target.data_id,
+      &target.group,
+      target.tenant(),
+      target.tag(),
+    );
+    self.update_cache(key, None).await;
+    Ok(())
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self
+      .cache
+      .iter()
+      .map(|(key, config)| CacheEntry {
+        key: key.as_ref().clone(),
+        config,
+        version: None,
+      })
+      .collect()
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    for entry in entries {
+      // entries with a version are dumped by other providers
+      if entry.version.is_some() {
+        continue;
+      }
+      // only the cache is warmed, the origin will be asked when refreshing
+      self.cache.insert(entry.key, entry.config).await;
+    }
+  }
+}
+
+fn cache_key(data_id: &str, group: &str, tenant: Option<&str>, tag: Option<&str>) -> String {
+  format!(
+    "{}/{}/{}/{}",
+    tenant.unwrap_or(""),
+    group,
+    data_id,
+    tag.unwrap_or("")
+  )
+}
+
+/// Map a non-200 response of the origin to an error.
+async fn error_of(res: Response) -> ProviderError {
+  match res.status() {
+    StatusCode::NOT_FOUND => ProviderError::NotFound,
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => match res.text().await {
+      Ok(msg) => ProviderError::Unauthorized(msg),
+      Err(e) => e.into(),
+    },
+    status => ProviderError::Upstream(format!("origin responded with {}", status)),
+  }
 }
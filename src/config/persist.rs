@@ -0,0 +1,92 @@
+use super::{provider::ConfigProvider, Config};
+use lambda_extension::tracing::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{io, sync::Arc, time::Duration};
+use tokio::{
+  fs,
+  sync::{mpsc, oneshot},
+  time::{interval_at, Instant, MissedTickBehavior},
+};
+
+/// A cached config dumped by a [`ConfigProvider`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheEntry {
+  /// The cache key of the provider.
+  pub key: String,
+  pub config: Arc<Config>,
+  /// The version used by the provider to validate the entry, e.g. the metadata of the file in fs mode.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub version: Option<String>,
+}
+
+/// Read the snapshot from `path` and warm the cache of the provider.
+pub async fn restore(cp: &impl ConfigProvider, path: &str) {
+  let entries = match fs::read(path).await {
+    Ok(bytes) => match serde_json::from_slice::<Vec<CacheEntry>>(&bytes) {
+      Ok(entries) => entries,
+      Err(e) => {
+        warn!(path, error = %e, "invalid cache snapshot");
+        return;
+      }
+    },
+    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+      debug!(path, "cache snapshot not found");
+      return;
+    }
+    Err(e) => {
+      warn!(path, error = %e, "failed to read cache snapshot");
+      return;
+    }
+  };
+  debug!(path, count = entries.len(), "restore cache snapshot");
+  cp.restore(entries).await;
+}
+
+/// Write the cache of the provider to `path`.
+async fn save(cp: &impl ConfigProvider, path: &str) -> io::Result<()> {
+  let entries = cp.snapshot();
+  // write to a temp file then rename it, so a crash won't leave a broken snapshot
+  let tmp = format!("{}.tmp", path);
+  fs::write(&tmp, serde_json::to_vec(&entries)?).await?;
+  fs::rename(&tmp, path).await?;
+  debug!(path, count = entries.len(), "cache snapshot saved");
+  Ok(())
+}
+
+/// Spawn a task to save the cache snapshot every `period` (if not `None`)
+/// and whenever a sender is received from the returned channel.
+/// The received sender is notified after the snapshot is saved.
+pub fn spawn_persister(
+  cp: impl ConfigProvider + 'static,
+  path: String,
+  period: Option<Duration>,
+) -> mpsc::Sender<oneshot::Sender<()>> {
+  let (persist_tx, mut persist_rx) = mpsc::channel::<oneshot::Sender<()>>(1);
+
+  tokio::spawn(async move {
+    let mut ticker = period.map(|period| {
+      let mut ticker = interval_at(Instant::now() + period, period);
+      ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      ticker
+    });
+    loop {
+      let done_tx = tokio::select! {
+        done_tx = persist_rx.recv() => {
+          let Some(done_tx) = done_tx else { break };
+          Some(done_tx)
+        }
+        _ = async { ticker.as_mut().unwrap().tick().await }, if ticker.is_some() => None,
+      };
+      if let Err(e) = save(&cp, &path).await {
+        warn!(path, error = %e, "failed to save cache snapshot");
+      }
+      if let Some(done_tx) = done_tx {
+        // it's ok if the caller stops waiting
+        done_tx.send(()).ok();
+      }
+    }
+    debug!("persister is stopped");
+  });
+
+  persist_tx
+}
@@ -1,14 +1,64 @@
-use super::Config;
-use lambda_extension::Error;
+use super::{persist::CacheEntry, target::Target, Config};
+use crate::error::ProviderError;
 use std::{future::Future, sync::Arc};
 
 /// This should be cheap to clone.
 pub trait ConfigProvider: Clone + Send + Sync {
+  /// Tagged and untagged variants of a config are different configs.
   fn get(
     &mut self,
     data_id: &str,
     group: &str,
     tenant: Option<&str>,
+    tag: Option<&str>,
     refresh: bool,
-  ) -> impl Future<Output = Result<Arc<Config>, Error>> + Send;
+  ) -> impl Future<Output = Result<Arc<Config>, ProviderError>> + Send;
+
+  /// Dump the cached configs so they can be persisted.
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    vec![]
+  }
+
+  /// Called by the target manager with the targets before refreshing them,
+  /// e.g. to take a consistent snapshot of the source so all targets in a refresh come from it,
+  /// or to fetch the targets in bulk.
+  /// Return `true` if listeners should be notified of changes together after all targets are refreshed,
+  /// e.g. changes from the same snapshot, otherwise they are notified as soon as each target is refreshed.
+  /// Refreshes and prefetches are serialized by the target manager, so they never overlap.
+  /// The default implementation does nothing and returns `false`.
+  fn begin_refresh(&self, _targets: &[Target]) -> impl Future<Output = bool> + Send {
+    async { false }
+  }
+
+  /// Called by the target manager after refreshing the targets, even if the refresh is cancelled,
+  /// e.g. to drop what was fetched in bulk so it's not served after the refresh.
+  /// The default implementation does nothing.
+  fn end_refresh(&self) -> impl Future<Output = ()> + Send {
+    async {}
+  }
+
+  /// Publish a config, `content_type` is detected from the data id if not specified.
+  /// If `cas_md5` is specified, the config is only published if the md5 of the current config matches,
+  /// otherwise [`ProviderError::Conflict`] is returned.
+  /// The default implementation returns [`ProviderError::Unsupported`].
+  fn publish(
+    &self,
+    _target: &Target,
+    _content: String,
+    _content_type: Option<&str>,
+    _cas_md5: Option<&str>,
+  ) -> impl Future<Output = Result<Arc<Config>, ProviderError>> + Send {
+    async { Err(ProviderError::Unsupported) }
+  }
+
+  /// Remove a config.
+  /// The default implementation returns [`ProviderError::Unsupported`].
+  fn remove(&self, _target: &Target) -> impl Future<Output = Result<(), ProviderError>> + Send {
+    async { Err(ProviderError::Unsupported) }
+  }
+
+  /// Warm the cache with persisted configs.
+  fn restore(&self, _entries: Vec<CacheEntry>) -> impl Future<Output = ()> + Send {
+    async {}
+  }
 }
@@ -0,0 +1,207 @@
+use super::{content_type_of, persist::CacheEntry, provider::ConfigProvider, Config};
+use crate::{aws::Signer, error::ProviderError};
+use chrono::DateTime;
+use moka::future::Cache;
+use reqwest::{
+  header::{HeaderValue, ETAG, IF_NONE_MATCH, LAST_MODIFIED},
+  Client, StatusCode,
+};
+use std::sync::Arc;
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+struct CacheValue {
+  /// The ETag of the object, sent as `If-None-Match` when refreshing.
+  etag: Arc<String>,
+  config: Arc<Config>,
+}
+
+/// Read configs from S3 or an S3-compatible object storage,
+/// with the same layout as [`super::fs::FsConfigProvider`] under the prefix.
+#[derive(Clone, Debug)]
+pub struct S3ConfigProvider {
+  /// Moka cache keyed by the object key, which is cheap to clone.
+  cache: Cache<String, CacheValue>,
+  /// Reqwest client, which is cheap to clone.
+  client: Client,
+  signer: Signer,
+  /// The url of the bucket with a trailing slash.
+  bucket_url: Arc<String>,
+  /// The prefix of object keys, e.g. `nacos/`.
+  prefix: Arc<String>,
+}
+
+impl S3ConfigProvider {
+  /// Use the virtual-hosted style url of AWS if `endpoint` is not specified,
+  /// otherwise use the path style url, which is supported by most S3-compatible storages (e.g. MinIO).
+  pub fn new(
+    size: u64,
+    bucket: &str,
+    prefix: String,
+    endpoint: Option<&str>,
+    signer: Signer,
+  ) -> Self {
+    let bucket_url = match endpoint {
+      Some(endpoint) => format!("{}/{}/", endpoint.trim_end_matches('/'), bucket),
+      None => format!("https://{}.s3.{}.amazonaws.com/", bucket, signer.region()),
+    };
+    S3ConfigProvider {
+      cache: Cache::new(size),
+      client: Client::new(),
+      signer,
+      bucket_url: Arc::new(bucket_url),
+      prefix: Arc::new(prefix),
+    }
+  }
+
+  /// Get the object, return `Ok(None)` if it's not modified since the `etag`.
+  async fn fetch(
+    &self,
+    key: &str,
+    data_id: &str,
+    etag: Option<&str>,
+  ) -> Result<Option<CacheValue>, ProviderError> {
+    // encode each segment of the key, S3 signs the path as is
+    let path = key
+      .split('/')
+      .map(|segment| urlencoding::encode(segment))
+      .collect::<Vec<_>>()
+      .join("/");
+    let mut req = self
+      .client
+      .get(format!("{}{}", self.bucket_url, path))
+      .build()?;
+    if let Some(Ok(etag)) = etag.map(HeaderValue::from_str) {
+      req.headers_mut().insert(IF_NONE_MATCH, etag);
+    }
+    self.signer.sign(&mut req);
+
+    let res = self.client.execute(req).await?;
+    match res.status() {
+      StatusCode::OK => {
+        let etag = res
+          .headers()
+          .get(ETAG)
+          .and_then(|v| v.to_str().ok())
+          .unwrap_or_default()
+          .to_string();
+        let last_modified = res
+          .headers()
+          .get(LAST_MODIFIED)
+          .and_then(|v| v.to_str().ok())
+          .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+          .map_or(0, |t| t.timestamp_millis());
+        let config = Config::new(res.text().await?)
+          .with_content_type(content_type_of(data_id))
+          .with_last_modified(last_modified);
+        Ok(Some(CacheValue {
+          etag: Arc::new(etag),
+          config: Arc::new(config),
+        }))
+      }
+      StatusCode::NOT_MODIFIED => Ok(None),
+      StatusCode::NOT_FOUND => Err(ProviderError::NotFound),
+      // S3 responds with 403 instead of 404 if the role is not allowed to list the bucket
+      StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(ProviderError::Unauthorized(
+        format!("s3 responded with {}", res.status()),
+      )),
+      status => Err(ProviderError::Upstream(format!(
+        "s3 responded with {}",
+        status
+      ))),
+    }
+  }
+}
+
+impl ConfigProvider for S3ConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let key = match tag {
+      Some(tag) => format!(
+        "{}{}/{}/tags/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        tag,
+        data_id
+      ),
+      None => format!(
+        "{}{}/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        data_id
+      ),
+    };
+
+    let cached = self.cache.get(&key).await;
+    if !refresh {
+      if let Some(value) = cached {
+        return Ok(value.config);
+      }
+    }
+
+    // only transfer the object if it's changed
+    let etag = cached.as_ref().map(|value| value.etag.as_str());
+    match self.fetch(&key, data_id, etag).await {
+      Ok(Some(value)) => {
+        let config = value.config.clone();
+        self.cache.insert(key, value).await;
+        Ok(config)
+      }
+      Ok(None) => match cached {
+        Some(value) => Ok(value.config),
+        None => Err(ProviderError::Upstream(
+          "s3 responded with 304 without a cached object".to_string(),
+        )),
+      },
+      Err(ProviderError::NotFound) => {
+        // the object might be deleted, don't serve the cached content anymore
+        self.cache.invalidate(&key).await;
+        Err(ProviderError::NotFound)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  fn snapshot(&self) -> Vec<CacheEntry> {
+    self
+      .cache
+      .iter()
+      .map(|(key, value)| CacheEntry {
+        key: format!("{}{}", self.bucket_url, key),
+        config: value.config,
+        version: Some(value.etag.to_string()),
+      })
+      .collect()
+  }
+
+  async fn restore(&self, entries: Vec<CacheEntry>) {
+    for entry in entries {
+      // entries of other providers are not keyed by the url of this bucket
+      let (Some(key), Some(etag)) = (
+        entry.key.strip_prefix(self.bucket_url.as_str()),
+        entry.version,
+      ) else {
+        continue;
+      };
+      // the object will be validated by the etag when refreshing
+      self
+        .cache
+        .insert(
+          key.to_string(),
+          CacheValue {
+            etag: Arc::new(etag),
+            config: entry.config,
+          },
+        )
+        .await;
+    }
+  }
+}
@@ -0,0 +1,176 @@
+use super::{content_type_of, provider::ConfigProvider, Config};
+use crate::{aws::JsonClient, error::ProviderError};
+use lambda_extension::tracing::debug;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SecretRequest<'a> {
+  secret_id: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  version_stage: Option<&'a str>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DescribeSecretResponse {
+  #[serde(default)]
+  version_ids_to_stages: HashMap<String, Vec<String>>,
+  /// Set if the secret is scheduled for deletion.
+  deleted_date: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSecretValueResponse {
+  version_id: String,
+  secret_string: Option<String>,
+  /// Base64 encoded.
+  secret_binary: Option<String>,
+  /// In seconds since the unix epoch.
+  #[serde(default)]
+  created_date: f64,
+}
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+struct CacheValue {
+  version_id: Arc<String>,
+  config: Arc<Config>,
+}
+
+/// Read configs from AWS Secrets Manager,
+/// where the config of a target is the secret `{prefix}{tenant}/{group}/{dataId}`,
+/// or `{prefix}{tenant}/{group}/tags/{tag}/{dataId}` if tagged.
+/// Secrets are never persisted or logged.
+#[derive(Clone, Debug)]
+pub struct SecretsConfigProvider {
+  /// Moka cache keyed by the secret id, which is cheap to clone.
+  cache: Cache<String, CacheValue>,
+  client: JsonClient,
+  /// The prefix of secret names, e.g. `nacos/`.
+  prefix: Arc<String>,
+  /// The version stage to serve, e.g. `AWSCURRENT`.
+  version_stage: Arc<String>,
+}
+
+impl SecretsConfigProvider {
+  pub fn new(size: u64, client: JsonClient, prefix: String, version_stage: String) -> Self {
+    SecretsConfigProvider {
+      cache: Cache::new(size),
+      client,
+      prefix: Arc::new(prefix),
+      version_stage: Arc::new(version_stage),
+    }
+  }
+
+  /// Return the id of the version in the stage, without reading the secret.
+  async fn current_version_id(&self, secret_id: &str) -> Result<String, ProviderError> {
+    let res: DescribeSecretResponse = self
+      .client
+      .call(
+        "DescribeSecret",
+        &SecretRequest {
+          secret_id,
+          version_stage: None,
+        },
+      )
+      .await?;
+    if res.deleted_date.is_some() {
+      return Err(ProviderError::NotFound);
+    }
+    res
+      .version_ids_to_stages
+      .into_iter()
+      .find(|(_, stages)| stages.contains(&self.version_stage))
+      .map(|(version_id, _)| version_id)
+      .ok_or(ProviderError::NotFound)
+  }
+
+  async fn fetch(&self, secret_id: &str, data_id: &str) -> Result<CacheValue, ProviderError> {
+    let res: GetSecretValueResponse = self
+      .client
+      .call(
+        "GetSecretValue",
+        &SecretRequest {
+          secret_id,
+          version_stage: Some(&self.version_stage),
+        },
+      )
+      .await?;
+    // binary secrets are served as is, in base64
+    let content = res.secret_string.or(res.secret_binary).unwrap_or_default();
+    Ok(CacheValue {
+      version_id: Arc::new(res.version_id),
+      config: Arc::new(
+        Config::new(content)
+          .with_content_type(content_type_of(data_id))
+          .with_last_modified((res.created_date * 1000.0) as i64),
+      ),
+    })
+  }
+}
+
+impl ConfigProvider for SecretsConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let secret_id = match tag {
+      Some(tag) => format!(
+        "{}{}/{}/tags/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        tag,
+        data_id
+      ),
+      None => format!(
+        "{}{}/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        data_id
+      ),
+    };
+
+    let cached = self.cache.get(&secret_id).await;
+    if !refresh {
+      if let Some(value) = cached {
+        return Ok(value.config);
+      }
+    }
+
+    let result = match &cached {
+      // only read the secret if it's rotated
+      Some(value) => match self.current_version_id(&secret_id).await {
+        Ok(version_id) if version_id == *value.version_id => return Ok(value.config.clone()),
+        Ok(_) => self.fetch(&secret_id, data_id).await,
+        Err(e) => Err(e),
+      },
+      None => self.fetch(&secret_id, data_id).await,
+    };
+    match result {
+      Ok(value) => {
+        if let Some(cached) = &cached {
+          debug!(secret_id, from = %cached.version_id, to = %value.version_id, "secret rotated");
+        }
+        let config = value.config.clone();
+        self.cache.insert(secret_id, value).await;
+        Ok(config)
+      }
+      Err(ProviderError::NotFound) => {
+        // the secret might be deleted, don't serve the cached content anymore
+        self.cache.invalidate(&secret_id).await;
+        Err(ProviderError::NotFound)
+      }
+      Err(e) => Err(e),
+    }
+  }
+}
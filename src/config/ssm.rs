@@ -0,0 +1,240 @@
+use super::{content_type_of, provider::ConfigProvider, target::Target, Config};
+use crate::{aws::JsonClient, error::ProviderError};
+use futures::{stream, StreamExt};
+use lambda_extension::tracing::{debug, warn};
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+  collections::{BTreeSet, HashMap},
+  sync::{Arc, RwLock},
+};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Parameter {
+  name: String,
+  value: String,
+  /// In seconds since the unix epoch.
+  #[serde(default)]
+  last_modified_date: f64,
+}
+
+impl Parameter {
+  fn into_config(self) -> Config {
+    let data_id = self.name.rsplit('/').next().unwrap_or_default();
+    Config::new(self.value)
+      .with_content_type(content_type_of(data_id))
+      .with_last_modified((self.last_modified_date * 1000.0) as i64)
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParameterResponse {
+  parameter: Parameter,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParametersByPathRequest<'a> {
+  path: &'a str,
+  recursive: bool,
+  with_decryption: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  next_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParametersByPathResponse {
+  #[serde(default)]
+  parameters: Vec<Parameter>,
+  next_token: Option<String>,
+}
+
+/// Parameters keyed by the name.
+type Parameters = HashMap<String, Arc<Config>>;
+
+/// Read configs from SSM Parameter Store or a compatible key/value store,
+/// where the config of a target is the parameter `{prefix}/{tenant}/{group}/{dataId}`,
+/// or `{prefix}/{tenant}/{group}/tags/{tag}/{dataId}` if tagged.
+/// SecureString parameters are decrypted, so the cache is never persisted.
+#[derive(Clone, Debug)]
+pub struct SsmConfigProvider {
+  /// Moka cache keyed by the parameter name, which is cheap to clone.
+  cache: Cache<String, Arc<Config>>,
+  client: JsonClient,
+  /// The path of all parameters without a trailing slash, e.g. `/nacos`.
+  prefix: Arc<String>,
+  /// The maximum number of paths to fetch concurrently when refreshing.
+  concurrency: usize,
+  /// Parameters fetched in bulk at the beginning of the current refresh,
+  /// keyed by the parent path, then by the parameter name.
+  /// A path is missing if it was not fetched, then its targets are fetched one by one.
+  /// This is cleared after the refresh, so a later cache miss doesn't take an outdated listing as authoritative.
+  batch: Arc<RwLock<HashMap<String, Parameters>>>,
+}
+
+impl SsmConfigProvider {
+  pub fn new(size: u64, client: JsonClient, prefix: &str) -> Self {
+    SsmConfigProvider {
+      cache: Cache::new(size),
+      client,
+      prefix: Arc::new(prefix.trim_end_matches('/').to_string()),
+      concurrency: usize::MAX,
+      batch: Default::default(),
+    }
+  }
+
+  /// Fetch at most `concurrency` paths concurrently when refreshing, `0` means unlimited.
+  /// Otherwise SSM might throttle the bulk fetch, then every target would be fetched one by one.
+  pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+    self.concurrency = match concurrency {
+      0 => usize::MAX,
+      n => n,
+    };
+    self
+  }
+
+  fn name_of(&self, data_id: &str, group: &str, tenant: Option<&str>, tag: Option<&str>) -> String {
+    match tag {
+      Some(tag) => format!(
+        "{}/{}/{}/tags/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        tag,
+        data_id
+      ),
+      None => format!(
+        "{}/{}/{}/{}",
+        self.prefix,
+        tenant.unwrap_or("public"),
+        group,
+        data_id
+      ),
+    }
+  }
+
+  /// Get all parameters directly under the path, following the pagination.
+  async fn fetch_path(&self, path: &str) -> Result<Parameters, ProviderError> {
+    let mut parameters = HashMap::new();
+    let mut next_token = None;
+    loop {
+      let res: GetParametersByPathResponse = self
+        .client
+        .call(
+          "GetParametersByPath",
+          &GetParametersByPathRequest {
+            path,
+            recursive: false,
+            with_decryption: true,
+            next_token,
+          },
+        )
+        .await?;
+      for parameter in res.parameters {
+        parameters.insert(parameter.name.clone(), Arc::new(parameter.into_config()));
+      }
+      next_token = res.next_token;
+      if next_token.is_none() {
+        return Ok(parameters);
+      }
+    }
+  }
+
+  /// Get the parameter from the batch of the current refresh,
+  /// return `None` if its path was not fetched in bulk.
+  fn get_batched(&self, name: &str) -> Option<Result<Arc<Config>, ProviderError>> {
+    let (path, _) = name.rsplit_once('/')?;
+    let batch = self.batch.read().unwrap();
+    let parameters = batch.get(path)?;
+    Some(parameters.get(name).cloned().ok_or(ProviderError::NotFound))
+  }
+}
+
+impl ConfigProvider for SsmConfigProvider {
+  async fn get(
+    &mut self,
+    data_id: &str,
+    group: &str,
+    tenant: Option<&str>,
+    tag: Option<&str>,
+    refresh: bool,
+  ) -> Result<Arc<Config>, ProviderError> {
+    let name = self.name_of(data_id, group, tenant, tag);
+
+    if !refresh {
+      if let Some(config) = self.cache.get(&name).await {
+        return Ok(config);
+      }
+    }
+
+    let result = match self.get_batched(&name) {
+      Some(result) => result,
+      None => self
+        .client
+        .call::<GetParameterResponse>(
+          "GetParameter",
+          &json!({ "Name": name, "WithDecryption": true }),
+        )
+        .await
+        .map(|res| Arc::new(res.parameter.into_config())),
+    };
+    match result {
+      Ok(config) => {
+        self.cache.insert(name, config.clone()).await;
+        Ok(config)
+      }
+      Err(ProviderError::NotFound) => {
+        // the parameter might be deleted, don't serve the cached content anymore
+        self.cache.invalidate(&name).await;
+        Err(ProviderError::NotFound)
+      }
+      Err(e) => Err(e),
+    }
+  }
+
+  /// Fetch the parent paths of the targets in bulk, instead of one request per target.
+  async fn begin_refresh(&self, targets: &[Target]) -> bool {
+    let paths: BTreeSet<_> = targets
+      .iter()
+      .filter_map(|target| {
+        let name = self.name_of(
+          &target.data_id,
+          &target.group,
+          target.tenant(),
+          target.tag(),
+        );
+        name.rsplit_once('/').map(|(path, _)| path.to_string())
+      })
+      .collect();
+
+    let results: Vec<_> = stream::iter(paths)
+      .map(|path| async move {
+        let result = self.fetch_path(&path).await;
+        (path, result)
+      })
+      .buffer_unordered(self.concurrency)
+      .collect()
+      .await;
+    let mut batch = HashMap::new();
+    for (path, result) in results {
+      match result {
+        Ok(parameters) => {
+          debug!(path, count = parameters.len(), "parameters fetched");
+          batch.insert(path, parameters);
+        }
+        // targets under the path will be fetched one by one
+        Err(e) => warn!(path, error = %e, "failed to fetch parameters by path"),
+      }
+    }
+    *self.batch.write().unwrap() = batch;
+    false
+  }
+
+  async fn end_refresh(&self) {
+    self.batch.write().unwrap().clear();
+  }
+}
@@ -1,11 +1,20 @@
-use super::provider::ConfigProvider;
-use futures::future::join_all;
-use lambda_extension::tracing::debug;
+use super::{md5_of, provider::ConfigProvider};
+use crate::{error::ProviderError, naming::ServiceKey};
+use futures::{stream, StreamExt};
+use lambda_extension::tracing::{debug, warn};
 use std::{
   collections::{hash_map::Entry, HashMap},
-  sync::Arc,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::{
+  sync::{broadcast, mpsc, oneshot},
+  time::Instant,
 };
-use tokio::sync::{broadcast, mpsc};
 
 /// This is cheap to clone. This is `Send` and `Sync`.
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
@@ -13,6 +22,8 @@ pub struct Target {
   pub data_id: Arc<String>,
   pub group: Arc<String>,
   pub tenant: Option<Arc<String>>,
+  /// Only gRPC clients listen to tagged configs.
+  pub tag: Option<Arc<String>>,
 }
 
 impl Target {
@@ -29,22 +40,110 @@ impl Target {
   pub fn tenant(&self) -> Option<&str> {
     self.tenant.as_deref().map(|s| s.as_str())
   }
+
+  pub fn tag(&self) -> Option<&str> {
+    self.tag.as_deref().map(|s| s.as_str())
+  }
+}
+
+impl FromStr for Target {
+  type Err = String;
+
+  /// Parse `"{group}/{data_id}"` or `"{tenant}/{group}/{data_id}"`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let parts: Vec<_> = s.trim().split('/').collect();
+    let (tenant, group, data_id) = match parts[..] {
+      [group, data_id] => (None, group, data_id),
+      [tenant, group, data_id] => ((!tenant.is_empty()).then_some(tenant), group, data_id),
+      _ => return Err(format!("invalid target: {:?}", s)),
+    };
+    if group.is_empty() || data_id.is_empty() {
+      return Err(format!("invalid target: {:?}", s));
+    }
+    Ok(Target {
+      data_id: Arc::new(data_id.to_string()),
+      group: Arc::new(group.to_string()),
+      tenant: tenant.map(|t| Arc::new(t.to_string())),
+      tag: None,
+    })
+  }
+}
+
+/// Messages sent to the target manager.
+#[derive(Debug)]
+pub enum TargetMessage {
+  /// Register a target with the md5 the client has.
+  /// This also refreshes the last seen time of the target.
+  Register(Target, String),
+  /// Fetch these targets before any client listens to them, so the cache is warm when clients call in.
+  /// Changes of prefetched targets are not waited for until a client registers them.
+  /// The sender is dropped when the prefetch is done.
+  Prefetch(Vec<Target>, oneshot::Sender<()>),
+  /// A long-lived listener (a pending long-polling request or a gRPC connection) starts holding the target.
+  /// Held targets never expire.
+  Hold(Target),
+  /// The listener stops holding the target, the target will expire if it's not seen for a while.
+  Release(Target),
+  /// The listener stops holding the target, remove the target now if no one else holds it.
+  Unregister(Target),
+  /// The config of the target is changed locally (e.g. published by a client) with the new md5,
+  /// listeners will be notified.
+  Changed(Target, String),
+  /// Refresh these targets now (e.g. their files are changed), listeners will be notified if changed.
+  /// Unknown targets are ignored.
+  Refresh(Vec<Target>),
+}
+
+/// Events reported to the caller of a refresh through the `changed_tx`.
+#[derive(Debug)]
+pub enum RefreshEvent {
+  /// The target is changed.
+  /// The refresh won't be done until the client gets the latest config.
+  Changed(Target),
+  /// Failed to refresh the target.
+  Failed(Target),
+  /// Refreshing the target timed out.
+  TimedOut(Target),
+  /// The refresh deadline is exceeded, the number of targets not refreshed is attached.
+  DeadlineExceeded(usize),
+  /// The service is changed.
+  /// The refresh won't be done until a subscriber gets the latest service.
+  ServiceChanged(ServiceKey),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TargetManagerOptions {
+  /// Targets not held by any listener and not seen for this will be removed before refreshing.
+  /// If `None`, targets never expire.
+  pub ttl: Option<Duration>,
+  /// The maximum number of targets to refresh concurrently, `0` means unlimited.
+  pub concurrency: usize,
+  /// Stop refreshing targets after this, the refresh will complete with what it has.
+  pub deadline: Option<Duration>,
+  /// The timeout to refresh each target.
+  pub timeout: Option<Duration>,
 }
 
 struct TargetState {
-  client_md5: String,
+  /// `None` if the target is prefetched and no client has registered it.
+  client_md5: Option<String>,
   latest_md5: String,
-  changed_tx: Option<mpsc::Sender<()>>,
+  changed_tx: Option<mpsc::Sender<RefreshEvent>>,
+  /// How many long-lived listeners are holding this target.
+  holders: usize,
+  last_seen: Instant,
 }
 
 pub fn spawn_target_manager(
   cp: impl ConfigProvider + 'static,
-  mut refresh_rx: mpsc::Receiver<mpsc::Sender<()>>,
-) -> (mpsc::Sender<(Target, String)>, broadcast::Sender<Target>) {
+  mut refresh_rx: mpsc::Receiver<mpsc::Sender<RefreshEvent>>,
+  options: TargetManagerOptions,
+) -> (mpsc::Sender<TargetMessage>, broadcast::Sender<Target>) {
   // this channel is used to register listening targets to the target manager
-  let (target_tx, mut target_rx) = mpsc::channel::<(Target, String)>(1);
-  // this channel is used to send updated target from the target manager to the long connection
-  let (config_tx, _) = broadcast::channel(1);
+  let (target_tx, mut target_rx) = mpsc::channel::<TargetMessage>(1);
+  // this channel is used to send updated target from the target manager to the long connection,
+  // its capacity should be large enough to hold targets changed in one refresh
+  let (config_tx, _) = broadcast::channel(64);
 
   // spawn the target manager
   tokio::spawn({
@@ -53,54 +152,100 @@ pub fn spawn_target_manager(
       let mut targets = HashMap::new();
       loop {
         tokio::select! {
-          target = target_rx.recv() => {
-            debug!("register target: {:?}", target); // target might be None
-            let Some((target, md5)) = target else { break };
-            match targets.entry(target) {
-              Entry::Vacant(entry) => {
-                entry.insert(TargetState {
-                  client_md5: md5.clone(),
-                  latest_md5: md5,
-                  changed_tx: None,
-                });
+          message = target_rx.recv() => {
+            debug!("target message: {:?}", message); // message might be None
+            let Some(message) = message else { break };
+            match message {
+              TargetMessage::Register(target, md5) => match targets.entry(target) {
+                Entry::Vacant(entry) => {
+                  entry.insert(TargetState {
+                    client_md5: Some(md5.clone()),
+                    latest_md5: md5,
+                    changed_tx: None,
+                    holders: 0,
+                    last_seen: Instant::now(),
+                  });
+                },
+                Entry::Occupied(mut entry) => {
+                  let state = entry.get_mut();
+                  if md5 == state.latest_md5 {
+                    // client md5 matches the latest md5,
+                    // take and drop the sender to mark the refresh as done
+                    state.changed_tx.take();
+                  }
+                  state.client_md5 = Some(md5);
+                  state.last_seen = Instant::now();
+                },
               },
-              Entry::Occupied(mut entry) => {
-                let state = entry.get_mut();
-                if md5 == state.latest_md5 {
-                  // client md5 matches the latest md5,
-                  // take and drop the sender to mark the refresh as done
-                  state.changed_tx.take();
+              TargetMessage::Prefetch(prefetched, _done_tx) => {
+                // like refreshes, this is done in the loop so it never overlaps them
+                for (target, md5) in fetch(&cp, prefetched, options).await {
+                  // a registered target is already refreshed
+                  targets.entry(target).or_insert_with(|| TargetState {
+                    client_md5: None,
+                    latest_md5: md5,
+                    changed_tx: None,
+                    holders: 0,
+                    last_seen: Instant::now(),
+                  });
                 }
-                state.client_md5 = md5;
-              },
+              }
+              TargetMessage::Hold(target) => {
+                if let Some(state) = targets.get_mut(&target) {
+                  state.holders += 1;
+                }
+              }
+              TargetMessage::Release(target) => {
+                if let Some(state) = targets.get_mut(&target) {
+                  state.holders = state.holders.saturating_sub(1);
+                  state.last_seen = Instant::now();
+                }
+              }
+              TargetMessage::Unregister(target) => {
+                if let Entry::Occupied(mut entry) = targets.entry(target) {
+                  let state = entry.get_mut();
+                  state.holders = state.holders.saturating_sub(1);
+                  if state.holders == 0 {
+                    // this also drops the changed_tx, so the refresh won't wait for this target
+                    debug!("unregister target: {:?}", entry.key());
+                    entry.remove();
+                  }
+                }
+              }
+              TargetMessage::Changed(target, md5) => {
+                // no one listens to an unknown target
+                let Some(state) = targets.get_mut(&target) else { continue };
+                state.latest_md5 = md5;
+                if state.client_md5.as_ref().is_some_and(|client_md5| *client_md5 != state.latest_md5)
+                  && config_tx.send(target).is_err()
+                {
+                  debug!("config_tx.send failed, which means no long connection is listening");
+                }
+              }
+              TargetMessage::Refresh(changed) => {
+                // no one waits for this refresh, just drain the events
+                let (changed_tx, mut changed_rx) = mpsc::channel(1);
+                tokio::spawn(async move { while changed_rx.recv().await.is_some() {} });
+                let targets = targets.iter_mut().filter(|(target, _)| changed.contains(target));
+                refresh(&cp, targets, &config_tx, changed_tx, false, options).await;
+              }
             }
           }
           changed_tx = refresh_rx.recv() => {
             debug!("refreshing all targets: {:?}", changed_tx.is_some());
             let Some(changed_tx) = changed_tx else { break };
 
-            join_all(targets.iter_mut().map(|(target, state)| {
-              let mut cp = cp.clone();
-              let config_tx = config_tx.clone();
-              let changed_tx = changed_tx.clone();
-              async move {
-                if let Ok(config) = cp.get(&target.data_id, &target.group, target.tenant(), true).await {
-                  let new_md5 = config.md5();
-                  let client_md5 = &state.client_md5;
-                  if new_md5 != client_md5 {
-                    debug!(client_md5, new_md5, "md5 mismatch");
-                    state.latest_md5 = new_md5.to_owned();
-                    changed_tx.send(()).await.expect("changed_tx.send failed");
-                    state.changed_tx = Some(changed_tx.clone());
-                    // it's ok if the config_tx.send failed
-                    // it means the long connection is disconnected but might be reconnected later
-                    if config_tx.send(target.clone()).is_err() {
-                      debug!("config_tx.send failed, which means no long connection is listening");
-                    }
-                  }
+            if let Some(ttl) = options.ttl {
+              targets.retain(|target, state| {
+                let expired = state.holders == 0 && state.last_seen.elapsed() > ttl;
+                if expired {
+                  debug!("target expired: {:?}", target);
                 }
-              }
-            })).await;
+                !expired
+              });
+            }
+
+            refresh(&cp, targets.iter_mut(), &config_tx, changed_tx, true, options).await;
           }
         }
       }
@@ -110,3 +255,210 @@ pub fn spawn_target_manager(
 
   (target_tx, config_tx)
 }
+
+/// Refresh the targets and notify listeners of changed ones through `config_tx`.
+/// Changes and failures are reported through `changed_tx`.
+/// If `wait`, the `changed_tx` is kept by changed targets until their clients get the latest configs,
+/// otherwise the `changed_tx` of an ongoing refresh is kept, so its caller still waits for the clients.
+/// Listeners are notified as soon as each target is refreshed,
+/// or after all targets are refreshed if the provider asks for it in [`ConfigProvider::begin_refresh`],
+/// so they get changes from the same snapshot together.
+async fn refresh<'a>(
+  cp: &impl ConfigProvider,
+  targets: impl Iterator<Item = (&'a Target, &'a mut TargetState)>,
+  config_tx: &broadcast::Sender<Target>,
+  changed_tx: mpsc::Sender<RefreshEvent>,
+  wait: bool,
+  options: TargetManagerOptions,
+) {
+  let targets: Vec<_> = targets.collect();
+  let keys: Vec<_> = targets
+    .iter()
+    .map(|(target, _)| (*target).clone())
+    .collect();
+
+  let total = targets.len();
+  let done = AtomicUsize::new(0);
+  let changed = Mutex::new(Vec::new());
+  // beginning the refresh counts towards the deadline, since it might fetch from the source
+  let refresh = async {
+    let batched = begin_refresh(cp, &keys, options.timeout).await;
+    stream::iter(targets)
+      .for_each_concurrent(options.concurrency, |(target, state)| {
+        let mut cp = cp.clone();
+        let changed_tx = changed_tx.clone();
+        let done = &done;
+        let changed = &changed;
+        async move {
+          let get = cp.get(
+            &target.data_id,
+            &target.group,
+            target.tenant(),
+            target.tag(),
+            true,
+          );
+          let result = match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, get)
+              .await
+              .unwrap_or(Err(ProviderError::Timeout)),
+            None => get.await,
+          };
+          done.fetch_add(1, Ordering::Relaxed);
+
+          // a deleted config is also a change, whose md5 is empty
+          let Some(new_md5) = md5_of(&result) else {
+            if let Err(e) = result {
+              warn!(?target, error = %e, "failed to refresh target");
+              let event = match e {
+                ProviderError::Timeout => RefreshEvent::TimedOut(target.clone()),
+                _ => RefreshEvent::Failed(target.clone()),
+              };
+              changed_tx
+                .send(event)
+                .await
+                .expect("changed_tx.send failed");
+            }
+            return;
+          };
+          let Some(client_md5) = &state.client_md5 else {
+            // no client to notify, just keep the cache warm
+            state.latest_md5 = new_md5.to_owned();
+            return;
+          };
+          if new_md5 != client_md5 {
+            debug!(client_md5, new_md5, "md5 mismatch");
+            state.latest_md5 = new_md5.to_owned();
+            changed_tx
+              .send(RefreshEvent::Changed(target.clone()))
+              .await
+              .expect("changed_tx.send failed");
+            if wait {
+              state.changed_tx = Some(changed_tx.clone());
+            }
+            if batched {
+              changed.lock().unwrap().push(target.clone());
+            } else {
+              notify(config_tx, target.clone());
+            }
+          }
+        }
+      })
+      .await
+  };
+
+  match options.deadline {
+    Some(deadline) => {
+      if tokio::time::timeout(deadline, refresh).await.is_err() {
+        // targets being refreshed are cancelled, they will be refreshed again in the next refresh
+        let skipped = total - done.load(Ordering::Relaxed);
+        warn!(skipped, "refresh deadline exceeded");
+        changed_tx
+          .send(RefreshEvent::DeadlineExceeded(skipped))
+          .await
+          .expect("changed_tx.send failed");
+      }
+    }
+    None => refresh.await,
+  }
+  cp.end_refresh().await;
+
+  for target in changed.into_inner().unwrap() {
+    notify(config_tx, target);
+  }
+}
+
+/// Notify listeners that the target is changed.
+fn notify(config_tx: &broadcast::Sender<Target>, target: Target) {
+  // it's ok if the config_tx.send failed
+  // it means the long connection is disconnected but might be reconnected later
+  if config_tx.send(target).is_err() {
+    debug!("config_tx.send failed, which means no long connection is listening");
+  }
+}
+
+/// Call [`ConfigProvider::begin_refresh`] within the timeout,
+/// targets are refreshed one by one if it times out, and changes are not notified together.
+async fn begin_refresh(
+  cp: &impl ConfigProvider,
+  targets: &[Target],
+  timeout: Option<Duration>,
+) -> bool {
+  match timeout {
+    Some(timeout) => tokio::time::timeout(timeout, cp.begin_refresh(targets))
+      .await
+      .unwrap_or_else(|_| {
+        warn!("beginning the refresh timed out");
+        false
+      }),
+    None => cp.begin_refresh(targets).await,
+  }
+}
+
+/// Let the target manager fetch the targets and wait for it, see [`TargetMessage::Prefetch`].
+pub async fn prefetch(targets: Vec<Target>, target_tx: &mpsc::Sender<TargetMessage>) {
+  let (done_tx, done_rx) = oneshot::channel::<()>();
+  target_tx
+    .send(TargetMessage::Prefetch(targets, done_tx))
+    .await
+    .expect("target_tx.send failed");
+  // the sender is dropped when the prefetch is done
+  done_rx.await.ok();
+}
+
+/// Fetch the targets through the config provider and return the md5 of fetched ones.
+/// This is bounded by the deadline and timeouts of refreshes, since the extension is not registered yet.
+async fn fetch(
+  cp: &impl ConfigProvider,
+  targets: Vec<Target>,
+  options: TargetManagerOptions,
+) -> Vec<(Target, String)> {
+  let total = targets.len();
+  let done = AtomicUsize::new(0);
+  let fetched = Mutex::new(vec![]);
+  let prefetch = async {
+    begin_refresh(cp, &targets, options.timeout).await;
+    stream::iter(targets)
+      .for_each_concurrent(options.concurrency, |target| {
+        let mut cp = cp.clone();
+        let (done, fetched) = (&done, &fetched);
+        async move {
+          let get = cp.get(
+            &target.data_id,
+            &target.group,
+            target.tenant(),
+            target.tag(),
+            true,
+          );
+          let result = match options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, get)
+              .await
+              .unwrap_or(Err(ProviderError::Timeout)),
+            None => get.await,
+          };
+          done.fetch_add(1, Ordering::Relaxed);
+          let Some(md5) = md5_of(&result) else {
+            if let Err(e) = result {
+              warn!(?target, error = %e, "failed to prefetch target");
+            }
+            return;
+          };
+          debug!(?target, md5, "target prefetched");
+          fetched.lock().unwrap().push((target, md5.to_owned()));
+        }
+      })
+      .await
+  };
+
+  match options.deadline {
+    Some(deadline) => {
+      if tokio::time::timeout(deadline, prefetch).await.is_err() {
+        // targets not prefetched will be fetched when clients call in
+        let skipped = total - done.load(Ordering::Relaxed);
+        warn!(skipped, "prefetch deadline exceeded");
+      }
+    }
+    None => prefetch.await,
+  }
+  cp.end_refresh().await;
+  fetched.into_inner().unwrap()
+}
@@ -0,0 +1,57 @@
+use std::{fmt, io};
+
+/// Errors returned by providers.
+#[derive(Debug)]
+pub enum ProviderError {
+  /// The requested resource does not exist.
+  NotFound,
+  /// The provider is not allowed to access the resource, e.g. the origin responded with 403.
+  Unauthorized(String),
+  /// The provider didn't get the resource in time.
+  Timeout,
+  /// The upstream (e.g. the origin nacos server) failed or responded with an unexpected result.
+  Upstream(String),
+  /// Failed to read the resource from the file system.
+  Io(io::Error),
+  /// The resource was changed by others, e.g. the md5 mismatches when publishing a config with `casMd5`.
+  Conflict(String),
+  /// The provider doesn't support the operation, e.g. publishing a config to the file system.
+  Unsupported,
+}
+
+impl fmt::Display for ProviderError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProviderError::NotFound => write!(f, "not found"),
+      ProviderError::Unauthorized(msg) => write!(f, "unauthorized: {}", msg),
+      ProviderError::Timeout => write!(f, "timeout"),
+      ProviderError::Upstream(msg) => write!(f, "upstream error: {}", msg),
+      ProviderError::Io(e) => write!(f, "io error: {}", e),
+      ProviderError::Conflict(msg) => write!(f, "conflict: {}", msg),
+      ProviderError::Unsupported => write!(f, "unsupported operation"),
+    }
+  }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<io::Error> for ProviderError {
+  fn from(e: io::Error) -> Self {
+    match e.kind() {
+      io::ErrorKind::NotFound => ProviderError::NotFound,
+      io::ErrorKind::TimedOut => ProviderError::Timeout,
+      _ => ProviderError::Io(e),
+    }
+  }
+}
+
+impl From<reqwest::Error> for ProviderError {
+  fn from(e: reqwest::Error) -> Self {
+    if e.is_timeout() {
+      ProviderError::Timeout
+    } else {
+      // the url might contain the access token, don't leak it in logs
+      ProviderError::Upstream(e.without_url().to_string())
+    }
+  }
+}
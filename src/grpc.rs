@@ -1,5 +1,8 @@
 mod api_model;
+mod config;
+mod connection;
 mod nacos_proto;
+mod naming;
 mod server;
 mod utils;
 
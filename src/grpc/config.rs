@@ -0,0 +1,122 @@
+//! Handlers of config write requests.
+
+use super::{
+  api_model::{BaseResponse, ConfigPublishRequest, ConfigRemoveRequest, ERROR_CODE},
+  nacos_proto::Payload,
+  server::{RequestServerImpl, NO_RIGHT},
+  utils::{HandlerResult, PayloadUtils},
+};
+use crate::{
+  config::{
+    provider::ConfigProvider,
+    target::{Target, TargetMessage},
+  },
+  error::ProviderError,
+  naming::provider::NamingProvider,
+};
+use lambda_extension::{
+  tracing::{debug, warn},
+  Error,
+};
+use std::sync::Arc;
+
+impl<CP: ConfigProvider, NP: NamingProvider + 'static> RequestServerImpl<CP, NP> {
+  pub(super) async fn handle_config_publish(
+    &self,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: ConfigPublishRequest = serde_json::from_slice(&body_vec)?;
+    // the tag and the type are sent as additional params
+    let target = target(
+      &request.data_id,
+      &request.group,
+      &request.tenant,
+      request.get_addition_param("tag").map(|s| s.as_str()),
+    );
+    let content_type = request
+      .get_addition_param("type")
+      .filter(|s| !s.is_empty())
+      .map(|s| s.as_str());
+    let cas_md5 = request.cas_md5.as_deref().filter(|s| !s.is_empty());
+    debug!(?target, content_type, cas_md5, "ConfigPublishRequest");
+
+    let result = self
+      .cp
+      .publish(&target, request.content.to_string(), content_type, cas_md5)
+      .await
+      .map(|config| config.md5().to_owned());
+    self
+      .respond("ConfigPublishResponse", request.request_id, target, result)
+      .await
+  }
+
+  pub(super) async fn handle_config_remove(
+    &self,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: ConfigRemoveRequest = serde_json::from_slice(&body_vec)?;
+    let target = target(
+      &request.data_id,
+      &request.group,
+      &request.tenant,
+      request.tag.as_deref(),
+    );
+    debug!(?target, "ConfigRemoveRequest");
+
+    // the md5 of a removed config is empty
+    let result = self.cp.remove(&target).await.map(|_| String::new());
+    self
+      .respond("ConfigRemoveResponse", request.request_id, target, result)
+      .await
+  }
+
+  /// Notify listeners of the target if the write succeeded, and respond like nacos.
+  async fn respond(
+    &self,
+    r#type: &str,
+    request_id: Option<String>,
+    target: Target,
+    result: Result<String, ProviderError>,
+  ) -> Result<HandlerResult, Error> {
+    let response = match result {
+      Ok(md5) => {
+        self
+          .target_tx
+          .send(TargetMessage::Changed(target, md5))
+          .await?;
+        BaseResponse {
+          request_id,
+          ..BaseResponse::build_success_response()
+        }
+      }
+      Err(e) => {
+        warn!(?target, error = %e, "{}", r#type);
+        let code = match e {
+          ProviderError::Unauthorized(_) => NO_RIGHT,
+          _ => ERROR_CODE,
+        };
+        BaseResponse {
+          request_id,
+          ..BaseResponse::build_error_response(code, e.to_string())
+        }
+      }
+    };
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      r#type,
+      serde_json::to_string(&response)?,
+    )))
+  }
+}
+
+fn target(data_id: &str, group: &str, tenant: &str, tag: Option<&str>) -> Target {
+  Target {
+    data_id: Arc::new(data_id.to_owned()),
+    group: Arc::new(group.to_owned()),
+    tenant: (!tenant.is_empty()).then(|| Arc::new(tenant.to_owned())),
+    tag: tag
+      .filter(|t| !t.is_empty())
+      .map(|t| Arc::new(t.to_owned())),
+  }
+}
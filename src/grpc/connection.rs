@@ -0,0 +1,215 @@
+use crate::{
+  config::target::Target,
+  naming::{Instance, ServiceKey},
+};
+use std::{
+  collections::{HashMap, HashSet},
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::time::Instant;
+
+/// A connection without a bi-stream is removed after this,
+/// so targets it listens to won't be held forever.
+pub const BI_STREAM_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub struct Connection {
+  pub id: String,
+  pub targets: HashSet<Target>,
+  /// Subscribed services.
+  pub services: HashSet<ServiceKey>,
+  /// Instances registered by the connection, which should be deregistered when the connection is closed.
+  pub instances: Vec<(ServiceKey, Instance)>,
+  /// Whether the bi-stream of the connection is opened.
+  bi_stream: bool,
+  created: Instant,
+}
+
+impl Connection {
+  fn new(id: String) -> Self {
+    Connection {
+      id,
+      targets: HashSet::new(),
+      services: HashSet::new(),
+      instances: vec![],
+      bi_stream: false,
+      created: Instant::now(),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  connections: HashMap<String, Connection>,
+  /// The latest connection id of each remote address.
+  ids: HashMap<SocketAddr, String>,
+  /// Makes ids unique if the remote address is unknown.
+  seq: u64,
+}
+
+/// Listening targets and subscribed services of each connection, keyed by the connection id.
+/// The id is returned by ServerCheck, and carried in the `connectionId` header of requests.
+/// Clients that don't send the header are identified by their remote address,
+/// since a nacos client sends unary requests and the bi-stream over the same connection.
+/// This is cheap to clone.
+#[derive(Clone, Debug, Default)]
+pub struct Connections(Arc<Mutex<Inner>>);
+
+impl Connections {
+  /// Create a connection and return its id.
+  pub fn create(&self, addr: Option<SocketAddr>) -> String {
+    let mut inner = self.0.lock().unwrap();
+    let millis = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+    let id = match addr {
+      // like nacos, the connection id is `{timestamp}_{ip}_{port}`
+      Some(addr) => format!("{}_{}_{}", millis, addr.ip(), addr.port()),
+      None => {
+        inner.seq += 1;
+        format!("{}_unknown_{}", millis, inner.seq)
+      }
+    };
+    if let Some(addr) = addr {
+      inner.ids.insert(addr, id.clone());
+    }
+    inner
+      .connections
+      .insert(id.clone(), Connection::new(id.clone()));
+    id
+  }
+
+  /// Return the id in the `connectionId` header, or the id of the remote address.
+  /// A connection is created for the remote address if it has none.
+  /// `None` if neither is known, so connections are never shared by clients.
+  pub fn resolve(&self, addr: Option<SocketAddr>, header: Option<&str>) -> Option<String> {
+    if let Some(id) = header.filter(|id| !id.is_empty()) {
+      self
+        .0
+        .lock()
+        .unwrap()
+        .connections
+        .entry(id.to_string())
+        .or_insert_with(|| Connection::new(id.to_string()));
+      return Some(id.to_string());
+    }
+    let addr = addr?;
+    let id = self.0.lock().unwrap().ids.get(&addr).cloned();
+    Some(id.unwrap_or_else(|| self.create(Some(addr))))
+  }
+
+  /// Mark the bi-stream of the connection as opened, so it won't be removed as idle.
+  pub fn open_bi_stream(&self, id: &str) {
+    self.with_connection(id, |conn| conn.bi_stream = true)
+  }
+
+  /// Return `true` if the target is newly listened by the connection.
+  pub fn listen(&self, id: &str, target: Target) -> bool {
+    self.with_connection(id, |conn| conn.targets.insert(target))
+  }
+
+  /// Return `true` if the target was listened by the connection.
+  pub fn unlisten(&self, id: &str, target: &Target) -> bool {
+    self
+      .0
+      .lock()
+      .unwrap()
+      .connections
+      .get_mut(id)
+      .is_some_and(|conn| conn.targets.remove(target))
+  }
+
+  pub fn is_listening(&self, id: &str, target: &Target) -> bool {
+    self
+      .0
+      .lock()
+      .unwrap()
+      .connections
+      .get(id)
+      .is_some_and(|conn| conn.targets.contains(target))
+  }
+
+  /// Return `true` if the service is newly subscribed by the connection.
+  pub fn subscribe(&self, id: &str, key: ServiceKey) -> bool {
+    self.with_connection(id, |conn| conn.services.insert(key))
+  }
+
+  /// Return `true` if the service was subscribed by the connection.
+  pub fn unsubscribe(&self, id: &str, key: &ServiceKey) -> bool {
+    self
+      .0
+      .lock()
+      .unwrap()
+      .connections
+      .get_mut(id)
+      .is_some_and(|conn| conn.services.remove(key))
+  }
+
+  pub fn is_subscribing(&self, id: &str, key: &ServiceKey) -> bool {
+    self
+      .0
+      .lock()
+      .unwrap()
+      .connections
+      .get(id)
+      .is_some_and(|conn| conn.services.contains(key))
+  }
+
+  /// Record an instance registered by the connection.
+  pub fn register(&self, id: &str, key: ServiceKey, instance: Instance) {
+    self.with_connection(id, |conn| {
+      conn
+        .instances
+        .retain(|(k, i)| !(k == &key && i.same_as(&instance)));
+      conn.instances.push((key, instance));
+    })
+  }
+
+  /// Forget an instance registered by the connection.
+  pub fn deregister(&self, id: &str, key: &ServiceKey, instance: &Instance) {
+    if let Some(conn) = self.0.lock().unwrap().connections.get_mut(id) {
+      conn
+        .instances
+        .retain(|(k, i)| !(k == key && i.same_as(instance)));
+    }
+  }
+
+  /// Remove the connection and return it, which is empty if the connection doesn't exist.
+  pub fn remove(&self, id: &str) -> Connection {
+    let mut inner = self.0.lock().unwrap();
+    inner.ids.retain(|_, v| v != id);
+    inner
+      .connections
+      .remove(id)
+      .unwrap_or_else(|| Connection::new(id.to_string()))
+  }
+
+  /// Remove and return connections which haven't opened a bi-stream within the grace period.
+  pub fn remove_idle(&self, grace_period: Duration) -> Vec<Connection> {
+    let mut inner = self.0.lock().unwrap();
+    let idle: Vec<_> = inner
+      .connections
+      .values()
+      .filter(|conn| !conn.bi_stream && conn.created.elapsed() > grace_period)
+      .map(|conn| conn.id.clone())
+      .collect();
+    inner.ids.retain(|_, id| !idle.contains(id));
+    idle
+      .iter()
+      .filter_map(|id| inner.connections.remove(id))
+      .collect()
+  }
+
+  /// The connection is created if it doesn't exist, e.g. it was removed as idle.
+  fn with_connection<R>(&self, id: &str, f: impl FnOnce(&mut Connection) -> R) -> R {
+    let mut inner = self.0.lock().unwrap();
+    let conn = inner
+      .connections
+      .entry(id.to_string())
+      .or_insert_with(|| Connection::new(id.to_string()));
+    f(conn)
+  }
+}
@@ -0,0 +1,359 @@
+//! Handlers of naming requests.
+
+use super::{
+  api_model::{
+    BatchInstanceRequest, BatchInstanceResponse, Instance as ApiInstance, InstanceRequest,
+    InstanceResponse, ServiceInfo, ServiceListRequest, ServiceListResponse, ServiceQueryRequest,
+    ServiceQueryResponse, SubscribeServiceRequest, SubscribeServiceResponse, SUCCESS_CODE,
+  },
+  nacos_proto::Payload,
+  server::{RequestServerImpl, NO_RIGHT},
+  utils::{HandlerResult, PayloadUtils},
+};
+use crate::{
+  config::provider::ConfigProvider,
+  error::ProviderError,
+  naming::{
+    now_millis, provider::NamingProvider, subscription::ServiceMessage, Instance, Owner, Service,
+    ServiceKey,
+  },
+};
+use lambda_extension::{
+  tracing::{debug, error},
+  Error,
+};
+use std::sync::Arc;
+
+// https://github.com/alibaba/nacos/blob/2.4.1/api/src/main/java/com/alibaba/nacos/api/naming/remote/NamingRemoteConstants.java
+const REGISTER_INSTANCE: &str = "registerInstance";
+const DE_REGISTER_INSTANCE: &str = "deregisterInstance";
+const BATCH_REGISTER_INSTANCE: &str = "batchRegisterInstance";
+
+const DEFAULT_GROUP: &str = "DEFAULT_GROUP";
+
+impl<CP: ConfigProvider, NP: NamingProvider + 'static> RequestServerImpl<CP, NP> {
+  pub(super) async fn handle_instance(
+    &self,
+    connection_id: &str,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: InstanceRequest = serde_json::from_slice(&body_vec)?;
+    let key = service_key(
+      &request.namespace,
+      &request.group_name,
+      &request.service_name,
+    );
+    let Some(instance) = request.instance.and_then(from_api_instance) else {
+      return Ok(HandlerResult::error(
+        400u16,
+        "instance is required".to_owned(),
+      ));
+    };
+    debug!(?key, ?instance, r#type = request.r#type, "InstanceRequest");
+
+    match request.r#type.as_deref() {
+      Some(REGISTER_INSTANCE) => {
+        self
+          .connections
+          .register(connection_id, key.clone(), instance.clone());
+        self
+          .np
+          .register(Owner::Connection(connection_id.to_string()), &key, instance);
+      }
+      Some(DE_REGISTER_INSTANCE) => {
+        self.connections.deregister(connection_id, &key, &instance);
+        self.np.deregister(
+          Owner::Connection(connection_id.to_string()),
+          &key,
+          &instance,
+        );
+      }
+      t => {
+        return Ok(HandlerResult::error(
+          400u16,
+          format!("unsupported request type {:?}", t),
+        ))
+      }
+    }
+    // it's ok if no bi-stream is connected
+    self.service_changed_tx.send(key).ok();
+
+    let response = InstanceResponse {
+      result_code: SUCCESS_CODE,
+      request_id: request.request_id,
+      r#type: request.r#type,
+      ..Default::default()
+    };
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      "InstanceResponse",
+      serde_json::to_string(&response)?,
+    )))
+  }
+
+  pub(super) async fn handle_batch_instance(
+    &self,
+    connection_id: &str,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: BatchInstanceRequest = serde_json::from_slice(&body_vec)?;
+    let key = service_key(
+      &request.namespace,
+      &request.group_name,
+      &request.service_name,
+    );
+    debug!(?key, r#type = request.r#type, "BatchInstanceRequest");
+
+    if request.r#type.as_deref() != Some(BATCH_REGISTER_INSTANCE) {
+      return Ok(HandlerResult::error(
+        400u16,
+        format!("unsupported request type {:?}", request.r#type),
+      ));
+    }
+    for instance in request
+      .instances
+      .unwrap_or_default()
+      .into_iter()
+      .filter_map(from_api_instance)
+    {
+      self
+        .connections
+        .register(connection_id, key.clone(), instance.clone());
+      self
+        .np
+        .register(Owner::Connection(connection_id.to_string()), &key, instance);
+    }
+    self.service_changed_tx.send(key).ok();
+
+    let response = BatchInstanceResponse {
+      result_code: SUCCESS_CODE,
+      request_id: request.request_id,
+      r#type: request.r#type,
+      ..Default::default()
+    };
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      "BatchInstanceResponse",
+      serde_json::to_string(&response)?,
+    )))
+  }
+
+  pub(super) async fn handle_subscribe_service(
+    &self,
+    connection_id: &str,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: SubscribeServiceRequest = serde_json::from_slice(&body_vec)?;
+    let key = service_key(
+      &request.namespace,
+      &request.group_name,
+      &request.service_name,
+    );
+    debug!(
+      ?key,
+      subscribe = request.subscribe,
+      "SubscribeServiceRequest"
+    );
+
+    let service = match self.np.clone().get(&key, false).await {
+      Ok(service) => service,
+      Err(e) => return Ok(error_result("SubscribeServiceRequest", e)),
+    };
+    if request.subscribe {
+      let message = if self.connections.subscribe(connection_id, key.clone()) {
+        ServiceMessage::Subscribe(key.clone(), service.checksum().to_owned())
+      } else {
+        ServiceMessage::Register(key.clone(), service.checksum().to_owned())
+      };
+      self.service_tx.send(message).await?;
+    } else if self.connections.unsubscribe(connection_id, &key) {
+      self
+        .service_tx
+        .send(ServiceMessage::Unsubscribe(key.clone()))
+        .await?;
+    }
+
+    let response = SubscribeServiceResponse {
+      result_code: SUCCESS_CODE,
+      request_id: request.request_id,
+      service_info: Some(service_info(
+        &key,
+        &service,
+        request.clusters.as_deref().unwrap_or_default(),
+        false,
+      )),
+      ..Default::default()
+    };
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      "SubscribeServiceResponse",
+      serde_json::to_string(&response)?,
+    )))
+  }
+
+  pub(super) async fn handle_service_query(
+    &self,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: ServiceQueryRequest = serde_json::from_slice(&body_vec)?;
+    let key = service_key(
+      &request.namespace,
+      &request.group_name,
+      &request.service_name,
+    );
+    debug!(?key, "ServiceQueryRequest");
+
+    let service = match self.np.clone().get(&key, false).await {
+      Ok(service) => service,
+      Err(e) => return Ok(error_result("ServiceQueryRequest", e)),
+    };
+    // refresh the service along with configs, so the cache won't be stale
+    self
+      .service_tx
+      .send(ServiceMessage::Register(
+        key.clone(),
+        service.checksum().to_owned(),
+      ))
+      .await?;
+
+    let response = ServiceQueryResponse {
+      result_code: SUCCESS_CODE,
+      request_id: request.request_id,
+      service_info: Some(service_info(
+        &key,
+        &service,
+        request.cluster.as_deref().unwrap_or_default(),
+        request.healthy_only.unwrap_or_default(),
+      )),
+      ..Default::default()
+    };
+    // https://github.com/alibaba/nacos/blob/2.4.1/api/src/main/java/com/alibaba/nacos/api/naming/remote/response/QueryServiceResponse.java
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      "QueryServiceResponse",
+      serde_json::to_string(&response)?,
+    )))
+  }
+
+  pub(super) async fn handle_service_list(&self, payload: Payload) -> Result<HandlerResult, Error> {
+    let body_vec = payload.body.unwrap_or_default().value;
+    let request: ServiceListRequest = serde_json::from_slice(&body_vec)?;
+    let key = service_key(&request.namespace, &request.group_name, &None);
+    debug!(namespace = key.namespace(), group = %key.group, "ServiceListRequest");
+
+    let names = match self
+      .np
+      .clone()
+      .list(key.namespace.as_deref().map(|s| s.as_str()), &key.group)
+      .await
+    {
+      Ok(names) => names,
+      Err(e) => return Ok(error_result("ServiceListRequest", e)),
+    };
+    let page_size = request.page_size as usize;
+    let response = ServiceListResponse {
+      result_code: SUCCESS_CODE,
+      request_id: request.request_id,
+      count: names.len(),
+      service_names: Some(
+        names
+          .into_iter()
+          .skip((request.page_no as usize).saturating_sub(1) * page_size)
+          .take(page_size)
+          .map(Arc::new)
+          .collect(),
+      ),
+      ..Default::default()
+    };
+    Ok(HandlerResult::success(PayloadUtils::build_payload(
+      "ServiceListResponse",
+      serde_json::to_string(&response)?,
+    )))
+  }
+}
+
+fn service_key(
+  namespace: &Option<String>,
+  group: &Option<String>,
+  name: &Option<String>,
+) -> ServiceKey {
+  ServiceKey::new(
+    namespace.as_deref(),
+    group
+      .as_deref()
+      .filter(|g| !g.is_empty())
+      .unwrap_or(DEFAULT_GROUP),
+    name.as_deref().unwrap_or_default(),
+  )
+}
+
+fn error_result(request: &str, e: ProviderError) -> HandlerResult {
+  error!(error = %e, "{}", request);
+  let code = match e {
+    ProviderError::Unauthorized(_) => NO_RIGHT,
+    _ => 500u16,
+  };
+  HandlerResult::error(code, e.to_string())
+}
+
+/// Encode the service like nacos does, `clusters` is comma-separated, empty for all clusters.
+pub(super) fn service_info(
+  key: &ServiceKey,
+  service: &Service,
+  clusters: &str,
+  healthy_only: bool,
+) -> ServiceInfo {
+  let selected: Vec<_> = clusters.split(',').filter(|c| !c.is_empty()).collect();
+  ServiceInfo {
+    name: Some(key.name.clone()),
+    group_name: Some(key.group.clone()),
+    clusters: Some(clusters.to_owned()),
+    cache_millis: 10000,
+    hosts: Some(
+      service
+        .select(&selected, healthy_only)
+        .map(|instance| to_api_instance(key, instance))
+        .collect(),
+    ),
+    last_ref_time: now_millis(),
+    checksum: Some(service.checksum().to_owned()),
+    all_ips: false,
+    reach_protection_threshold: false,
+  }
+}
+
+fn to_api_instance(key: &ServiceKey, instance: &Instance) -> ApiInstance {
+  ApiInstance {
+    instance_id: Some(Arc::new(instance.id(key))),
+    ip: Some(Arc::new(instance.ip.clone())),
+    port: instance.port,
+    weight: instance.weight as f32,
+    healthy: instance.healthy,
+    enabled: instance.enabled,
+    ephemeral: instance.ephemeral,
+    cluster_name: Some(instance.cluster_name.clone()),
+    service_name: Some(Arc::new(key.grouped_name())),
+    metadata: Some(Arc::new(instance.metadata.clone())),
+    ..Default::default()
+  }
+}
+
+/// Return `None` if the ip is missing.
+fn from_api_instance(instance: ApiInstance) -> Option<Instance> {
+  Some(Instance {
+    ip: instance.ip?.to_string(),
+    port: instance.port,
+    weight: instance.weight as f64,
+    healthy: instance.healthy,
+    enabled: instance.enabled,
+    ephemeral: instance.ephemeral,
+    cluster_name: instance
+      .cluster_name
+      .filter(|c| !c.is_empty())
+      .unwrap_or_else(|| "DEFAULT".to_owned()),
+    metadata: instance
+      .metadata
+      .map(|m| m.as_ref().clone())
+      .unwrap_or_default(),
+  })
+}
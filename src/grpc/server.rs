@@ -2,34 +2,100 @@ use super::{
   api_model::{
     BaseResponse, ConfigBatchListenRequest, ConfigChangeBatchListenResponse,
     ConfigChangeNotifyRequest, ConfigContext, ConfigQueryRequest, ConfigQueryResponse,
-    ServerCheckResponse, CONFIG_MODEL, ERROR_CODE, SUCCESS_CODE,
+    NotifySubscriberRequest, ServerCheckResponse, CONFIG_MODEL, ERROR_CODE, NAMING_MODEL,
+    NOT_FOUND, SUCCESS_CODE,
   },
+  connection::{Connection, Connections, BI_STREAM_GRACE_PERIOD},
   nacos_proto::{
     bi_request_stream_server::{BiRequestStream, BiRequestStreamServer},
     request_server::{Request, RequestServer},
     Payload,
   },
+  naming::service_info,
   utils::{HandlerResult, PayloadUtils},
 };
-use crate::config::{provider::ConfigProvider, target::Target};
+use crate::{
+  config::{
+    md5_of,
+    provider::ConfigProvider,
+    target::{Target, TargetMessage},
+  },
+  error::ProviderError,
+  naming::{provider::NamingProvider, subscription::ServiceMessage, Owner, ServiceKey},
+};
 use lambda_extension::{
   tracing::{debug, error, warn},
   Error,
 };
-use lazy_static::lazy_static;
 use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::{broadcast, mpsc};
+use tokio::{
+  sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+  },
+  time::interval,
+};
 use tonic::transport::Server;
 
+/// `service_tx` sends messages to the subscription manager,
+/// `service_changed_tx` broadcasts changed services to the bi-streams.
 pub fn spawn(
   addr: SocketAddr,
-  target_tx: mpsc::Sender<(Target, String)>,
+  target_tx: mpsc::Sender<TargetMessage>,
   config_tx: broadcast::Sender<Target>,
   cp: impl ConfigProvider + 'static,
+  np: impl NamingProvider + 'static,
+  service_tx: mpsc::Sender<ServiceMessage>,
+  service_changed_tx: broadcast::Sender<ServiceKey>,
 ) {
+  let connections = Connections::default();
+
+  // connections which never open a bi-stream are closed after the grace period
+  tokio::spawn({
+    let connections = connections.clone();
+    let target_tx = target_tx.clone();
+    let np = np.clone();
+    let service_tx = service_tx.clone();
+    let service_changed_tx = service_changed_tx.clone();
+    async move {
+      let mut interval = interval(BI_STREAM_GRACE_PERIOD);
+      loop {
+        interval.tick().await;
+        for connection in connections.remove_idle(BI_STREAM_GRACE_PERIOD) {
+          debug!(
+            id = connection.id,
+            "connection without a bi-stream is closed"
+          );
+          close(
+            connection,
+            &target_tx,
+            &np,
+            &service_tx,
+            &service_changed_tx,
+          )
+          .await;
+        }
+      }
+    }
+  });
+
   tokio::spawn(async move {
-    let request_server = RequestServerImpl { cp, target_tx };
-    let bi_request_stream_server = BiRequestStreamServerImpl { config_tx };
+    let request_server = RequestServerImpl {
+      cp,
+      np: np.clone(),
+      target_tx: target_tx.clone(),
+      service_tx: service_tx.clone(),
+      service_changed_tx: service_changed_tx.clone(),
+      connections: connections.clone(),
+    };
+    let bi_request_stream_server = BiRequestStreamServerImpl {
+      config_tx,
+      target_tx,
+      np,
+      service_tx,
+      service_changed_tx,
+      connections,
+    };
     Server::builder()
       .add_service(RequestServer::new(request_server))
       .add_service(BiRequestStreamServer::new(bi_request_stream_server))
@@ -39,34 +105,55 @@ pub fn spawn(
   });
 }
 
-// https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/config/config_type.rs#L3
-lazy_static! {
-  pub(crate) static ref CONFIG_TYPE_TEXT: Arc<String> = Arc::new("text".to_string());
-  // pub(crate) static ref CONFIG_TYPE_JSON: Arc<String> = Arc::new("json".to_string());
-  // pub(crate) static ref CONFIG_TYPE_XML: Arc<String> = Arc::new("xml".to_string());
-  // pub(crate) static ref CONFIG_TYPE_YAML: Arc<String> = Arc::new("yaml".to_string());
-  // pub(crate) static ref CONFIG_TYPE_HTML: Arc<String> = Arc::new("html".to_string());
-  // pub(crate) static ref CONFIG_TYPE_PROPERTIES: Arc<String> = Arc::new("properties".to_string());
-  // pub(crate) static ref CONFIG_TYPE_TOML: Arc<String> = Arc::new("toml".to_string());
-}
-
 // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/mod.rs#L44
 pub(crate) const HEALTH_CHECK_REQUEST: &str = "HealthCheckRequest";
 pub(crate) const SERVER_CHECK_REQUEST: &str = "ServerCheckRequest";
 pub(crate) const CONFIG_QUERY_REQUEST: &str = "ConfigQueryRequest";
 pub(crate) const CONFIG_BATCH_LISTEN_REQUEST: &str = "ConfigBatchListenRequest";
+pub(crate) const CONFIG_PUBLISH_REQUEST: &str = "ConfigPublishRequest";
+pub(crate) const CONFIG_REMOVE_REQUEST: &str = "ConfigRemoveRequest";
+pub(crate) const INSTANCE_REQUEST: &str = "InstanceRequest";
+pub(crate) const BATCH_INSTANCE_REQUEST: &str = "BatchInstanceRequest";
+pub(crate) const SUBSCRIBE_SERVICE_REQUEST: &str = "SubscribeServiceRequest";
+pub(crate) const SERVICE_QUERY_REQUEST: &str = "ServiceQueryRequest";
+pub(crate) const SERVICE_LIST_REQUEST: &str = "ServiceListRequest";
+
+// https://github.com/alibaba/nacos/blob/2.4.1/api/src/main/java/com/alibaba/nacos/api/config/remote/response/ConfigQueryResponse.java
+pub(crate) const CONFIG_NOT_FOUND: u16 = NOT_FOUND;
+// https://github.com/alibaba/nacos/blob/2.4.1/api/src/main/java/com/alibaba/nacos/api/exception/NacosException.java
+pub(crate) const NO_RIGHT: u16 = 403u16;
+/// The client reconnects if the connection is unregistered.
+const UN_REGISTER: u16 = 301u16;
 
-struct RequestServerImpl<CP> {
-  target_tx: mpsc::Sender<(Target, String)>,
-  cp: CP,
+/// The header of requests carrying the connection id returned by ServerCheck.
+const CONNECTION_ID: &str = "connectionId";
+
+pub(super) struct RequestServerImpl<CP, NP> {
+  pub(super) target_tx: mpsc::Sender<TargetMessage>,
+  pub(super) cp: CP,
+  pub(super) np: NP,
+  pub(super) service_tx: mpsc::Sender<ServiceMessage>,
+  pub(super) service_changed_tx: broadcast::Sender<ServiceKey>,
+  pub(super) connections: Connections,
 }
 
-impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
-  async fn handle(&self, payload: Payload) -> Result<HandlerResult, Error> {
+impl<CP: ConfigProvider + 'static, NP: NamingProvider + 'static> RequestServerImpl<CP, NP> {
+  async fn handle(
+    &self,
+    addr: Option<SocketAddr>,
+    payload: Payload,
+  ) -> Result<HandlerResult, Error> {
     let Some(url) = PayloadUtils::get_payload_type(&payload) else {
       // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/mod.rs#L237
       return Ok(HandlerResult::error(302u16, "empty type url".to_owned()));
     };
+    let header = payload
+      .metadata
+      .as_ref()
+      .and_then(|metadata| metadata.headers.get(CONNECTION_ID))
+      .map(String::as_str);
+    // requests bound to a connection are rejected if the connection can't be identified
+    let connection_id = || self.connections.resolve(addr, header);
 
     match url.as_str() {
       HEALTH_CHECK_REQUEST => {
@@ -81,7 +168,7 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
         // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/mod.rs#L200
         let response = ServerCheckResponse {
           result_code: SUCCESS_CODE,
-          connection_id: Some("".to_owned()),
+          connection_id: Some(self.connections.create(addr)),
           ..Default::default()
         };
         Ok(HandlerResult::success(PayloadUtils::build_payload(
@@ -97,7 +184,8 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
           ..Default::default()
         };
 
-        debug!(data_id = %request.data_id, group = %request.group, tenant = %request.tenant, "ConfigQueryRequest");
+        debug!(data_id = %request.data_id, group = %request.group, tenant = %request.tenant, tag = ?request.tag, "ConfigQueryRequest");
+        let tag = request.tag.as_deref().filter(|t| !t.is_empty());
 
         match self
           .cp
@@ -110,6 +198,7 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
             } else {
               Some(&request.tenant)
             },
+            tag,
             false,
           )
           .await
@@ -117,9 +206,11 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
           Ok(config) => {
             response.result_code = SUCCESS_CODE;
             response.content = config.content().to_owned().into();
-            response.content_type = Some(CONFIG_TYPE_TEXT.clone()); // TODO: use correct content type? does this matter?
-            response.last_modified = 0; // TODO: does this matter?
+            response.content_type = Some(config.content_type().clone());
+            response.last_modified = config.last_modified();
             response.md5 = Some(config.md5().to_owned().into());
+            response.beta = config.beta();
+            response.tag = tag.map(|t| t.to_owned());
 
             // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/config_query.rs#L85
             Ok(HandlerResult::success(PayloadUtils::build_payload(
@@ -127,10 +218,25 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
               serde_json::to_string(&response)?,
             )))
           }
+          Err(ProviderError::NotFound) => {
+            // like nacos, respond with ConfigQueryResponse so the client treats it as a missing config
+            // instead of an error
+            response.result_code = ERROR_CODE;
+            response.error_code = CONFIG_NOT_FOUND;
+            response.message = Some("config data not exist".to_owned());
+            debug!(data_id = %request.data_id, group = %request.group, tenant = %request.tenant, "config not found");
+            Ok(HandlerResult::success(PayloadUtils::build_payload(
+              "ConfigQueryResponse",
+              serde_json::to_string(&response)?,
+            )))
+          }
           Err(err) => {
             // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/config_query.rs#L90
             response.result_code = ERROR_CODE;
-            response.error_code = ERROR_CODE;
+            response.error_code = match err {
+              ProviderError::Unauthorized(_) => NO_RIGHT,
+              _ => ERROR_CODE,
+            };
             response.message = Some(err.to_string());
             error!(error = %err, "ConfigQueryRequest");
             Ok(HandlerResult::success(PayloadUtils::build_payload(
@@ -141,6 +247,9 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
         }
       }
       CONFIG_BATCH_LISTEN_REQUEST => {
+        let Some(id) = connection_id() else {
+          return Ok(unregistered(url));
+        };
         let body_vec = payload.body.unwrap_or_default().value;
         let request: ConfigBatchListenRequest = serde_json::from_slice(&body_vec)?;
 
@@ -150,7 +259,7 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
         };
 
         for item in request.config_listen_contexts {
-          debug!(data_id = %item.data_id, group = %item.group, tenant = %item.tenant, md5 = %item.md5, "ConfigBatchListenRequest");
+          debug!(data_id = %item.data_id, group = %item.group, tenant = %item.tenant, md5 = %item.md5, listen = request.listen, "ConfigBatchListenRequest");
           let target = Target {
             data_id: item.data_id.clone().into(),
             group: item.group.clone().into(),
@@ -159,13 +268,39 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
             } else {
               Some(item.tenant.clone().into())
             },
+            tag: item.tag.clone().filter(|t| !t.is_empty()).map(|t| t.into()),
           };
+
+          if !request.listen {
+            // the client removed the listener
+            if self.connections.unlisten(&id, &target) {
+              self
+                .target_tx
+                .send(TargetMessage::Unregister(target))
+                .await?;
+            }
+            continue;
+          }
           let cache = self
             .cp
             .clone()
-            .get(&target.data_id, &target.group, target.tenant(), false)
-            .await?;
-          if cache.md5() != item.md5.as_str() {
+            .get(
+              &target.data_id,
+              &target.group,
+              target.tenant(),
+              target.tag(),
+              false,
+            )
+            .await;
+          let Some(cached_md5) = md5_of(&cache) else {
+            if let Err(e) = cache {
+              error!(error = %e, "ConfigBatchListenRequest");
+            }
+            // register the target anyway, it will be checked again in the next refresh
+            self.register(&id, target, item.md5.to_string()).await?;
+            continue;
+          };
+          if cached_md5 != item.md5.as_str() {
             let obj = ConfigContext {
               data_id: item.data_id.into(),
               group: item.group.into(),
@@ -174,7 +309,7 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
             response.changed_configs.push(obj);
           }
           // register target to target_manager
-          self.target_tx.send((target, item.md5.to_string())).await?;
+          self.register(&id, target, item.md5.to_string()).await?;
         }
 
         response.result_code = SUCCESS_CODE;
@@ -185,6 +320,20 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
           serde_json::to_string(&response)?,
         )))
       }
+      CONFIG_PUBLISH_REQUEST => self.handle_config_publish(payload).await,
+      CONFIG_REMOVE_REQUEST => self.handle_config_remove(payload).await,
+      INSTANCE_REQUEST | BATCH_INSTANCE_REQUEST | SUBSCRIBE_SERVICE_REQUEST => {
+        let Some(id) = connection_id() else {
+          return Ok(unregistered(url));
+        };
+        match url.as_str() {
+          INSTANCE_REQUEST => self.handle_instance(&id, payload).await,
+          BATCH_INSTANCE_REQUEST => self.handle_batch_instance(&id, payload).await,
+          _ => self.handle_subscribe_service(&id, payload).await,
+        }
+      }
+      SERVICE_QUERY_REQUEST => self.handle_service_query(payload).await,
+      SERVICE_LIST_REQUEST => self.handle_service_list(payload).await,
       _ => {
         warn!("InvokerHandler not found for type:{}", url);
         // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/handler/mod.rs#L232
@@ -197,14 +346,33 @@ impl<CP: ConfigProvider + Clone + Send + 'static> RequestServerImpl<CP> {
   }
 }
 
+impl<CP, NP> RequestServerImpl<CP, NP> {
+  /// Register the target to the target manager,
+  /// and hold it as long as the connection listens to it.
+  async fn register(&self, id: &str, target: Target, md5: String) -> Result<(), Error> {
+    let newly_listened = self.connections.listen(id, target.clone());
+    self
+      .target_tx
+      .send(TargetMessage::Register(target.clone(), md5))
+      .await?;
+    if newly_listened {
+      self.target_tx.send(TargetMessage::Hold(target)).await?;
+    }
+    Ok(())
+  }
+}
+
 #[tonic::async_trait]
-impl<CP: ConfigProvider + 'static> Request for RequestServerImpl<CP> {
+impl<CP: ConfigProvider + 'static, NP: NamingProvider + 'static> Request
+  for RequestServerImpl<CP, NP>
+{
   async fn request(
     &self,
     request: tonic::Request<Payload>,
   ) -> Result<tonic::Response<Payload>, tonic::Status> {
+    let addr = request.remote_addr();
     let payload = request.into_inner();
-    let handle_result = self.handle(payload).await;
+    let handle_result = self.handle(addr, payload).await;
     match handle_result {
       Ok(res) => Ok(tonic::Response::new(res.payload)),
       Err(e) => {
@@ -218,23 +386,46 @@ impl<CP: ConfigProvider + 'static> Request for RequestServerImpl<CP> {
   }
 }
 
-pub struct BiRequestStreamServerImpl {
+pub struct BiRequestStreamServerImpl<NP> {
   config_tx: broadcast::Sender<Target>,
+  target_tx: mpsc::Sender<TargetMessage>,
+  np: NP,
+  service_tx: mpsc::Sender<ServiceMessage>,
+  service_changed_tx: broadcast::Sender<ServiceKey>,
+  connections: Connections,
 }
 
 #[tonic::async_trait]
-impl BiRequestStream for BiRequestStreamServerImpl {
+impl<NP: NamingProvider + 'static> BiRequestStream for BiRequestStreamServerImpl<NP> {
   type requestBiStreamStream =
     tokio_stream::wrappers::ReceiverStream<Result<Payload, tonic::Status>>;
 
   async fn request_bi_stream(
     &self,
-    _request: tonic::Request<tonic::Streaming<Payload>>,
+    request: tonic::Request<tonic::Streaming<Payload>>,
   ) -> Result<tonic::Response<Self::requestBiStreamStream>, tonic::Status> {
+    let header = request
+      .metadata()
+      .get(CONNECTION_ID.to_lowercase())
+      .and_then(|id| id.to_str().ok());
+    let Some(id) = self.connections.resolve(request.remote_addr(), header) else {
+      return Err(tonic::Status::failed_precondition(
+        "connection is unregistered",
+      ));
+    };
+    self.connections.open_bi_stream(&id);
+
     let (payload_tx, payload_rx) = tokio::sync::mpsc::channel(10);
     let r_stream = tokio_stream::wrappers::ReceiverStream::new(payload_rx);
 
+    let mut inbound = request.into_inner();
+    let connections = self.connections.clone();
+    let target_tx = self.target_tx.clone();
     let mut config_rx = self.config_tx.subscribe();
+    let mut np = self.np.clone();
+    let service_tx = self.service_tx.clone();
+    let service_changed_tx = self.service_changed_tx.clone();
+    let mut service_rx = self.service_changed_tx.subscribe();
     tokio::spawn(async move {
       // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/bistream_manage.rs#L87
       let mut next_request_id = {
@@ -249,26 +440,127 @@ impl BiRequestStream for BiRequestStreamServerImpl {
         }
       };
 
-      while let Ok(target) = config_rx.recv().await {
-        debug!("notifying config change: {:?}", target);
-        // TODO: check if the updated config is one of we are listening
-        let request = ConfigChangeNotifyRequest {
-          group: target.group,
-          data_id: target.data_id,
-          tenant: target.tenant.unwrap_or("".to_string().into()),
-          request_id: Some(next_request_id()),
-          module: Some(CONFIG_MODEL.to_string()),
-          ..Default::default()
+      loop {
+        let payload = tokio::select! {
+          message = inbound.message() => {
+            // the client might send ConnectionSetupRequest or responses of our requests, just ignore them
+            match message {
+              Ok(Some(_)) => continue,
+              Ok(None) | Err(_) => break,
+            }
+          }
+          target = config_rx.recv() => match target {
+            Ok(target) => {
+              if !connections.is_listening(&id, &target) {
+                continue;
+              }
+
+              debug!("notifying config change: {:?}", target);
+              let request = ConfigChangeNotifyRequest {
+                group: target.group,
+                data_id: target.data_id,
+                tenant: target.tenant.unwrap_or("".to_string().into()),
+                request_id: Some(next_request_id()),
+                module: Some(CONFIG_MODEL.to_string()),
+                ..Default::default()
+              };
+              // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/bistream_manage.rs#L251
+              PayloadUtils::build_payload(
+                "ConfigChangeNotifyRequest",
+                serde_json::to_string(&request).unwrap(),
+              )
+            }
+            Err(RecvError::Lagged(n)) => {
+              warn!(n, "bi-stream lagged behind config changes");
+              continue;
+            }
+            Err(RecvError::Closed) => break,
+          },
+          key = service_rx.recv() => match key {
+            Ok(key) => {
+              if !connections.is_subscribing(&id, &key) {
+                continue;
+              }
+              let service = match np.get(&key, false).await {
+                Ok(service) => service,
+                Err(e) => {
+                  warn!(?key, error = %e, "failed to get the changed service");
+                  continue;
+                }
+              };
+
+              debug!("notifying service change: {:?}", key);
+              // the refresh waiting for the change is done once the subscriber gets it
+              let _ = service_tx
+                .send(ServiceMessage::Register(key.clone(), service.checksum().to_owned()))
+                .await;
+              let request = NotifySubscriberRequest {
+                namespace: Some(Arc::new(key.namespace().to_string())),
+                service_name: Some(key.name.clone()),
+                group_name: Some(key.group.clone()),
+                service_info: Some(service_info(&key, &service, "", false)),
+                request_id: Some(next_request_id()),
+                module: Some(NAMING_MODEL.to_string()),
+                ..Default::default()
+              };
+              PayloadUtils::build_payload(
+                "NotifySubscriberRequest",
+                serde_json::to_string(&request).unwrap(),
+              )
+            }
+            Err(RecvError::Lagged(n)) => {
+              warn!(n, "bi-stream lagged behind service changes");
+              continue;
+            }
+            Err(RecvError::Closed) => break,
+          }
         };
-        // https://github.com/nacos-group/r-nacos/blob/c74dfab019b0771e38a28be7821b08021afd10c4/src/grpc/bistream_manage.rs#L251
-        let payload = PayloadUtils::build_payload(
-          "ConfigChangeNotifyRequest",
-          serde_json::to_string(&request).unwrap(),
-        );
-        payload_tx.send(Ok(payload)).await.unwrap();
+
+        if payload_tx.send(Ok(payload)).await.is_err() {
+          break;
+        }
       }
+
+      debug!(id, "bi-stream closed");
+      let connection = connections.remove(&id);
+      close(
+        connection,
+        &target_tx,
+        &np,
+        &service_tx,
+        &service_changed_tx,
+      )
+      .await;
     });
 
     Ok(tonic::Response::new(r_stream))
   }
 }
+
+/// Respond to a request whose connection can't be identified.
+fn unregistered(url: &str) -> HandlerResult {
+  warn!(url, "unknown connection");
+  HandlerResult::error(UN_REGISTER, "connection is unregistered".to_owned())
+}
+
+/// Release what the closed connection listens to, subscribes and registers.
+async fn close(
+  connection: Connection,
+  target_tx: &mpsc::Sender<TargetMessage>,
+  np: &impl NamingProvider,
+  service_tx: &mpsc::Sender<ServiceMessage>,
+  service_changed_tx: &broadcast::Sender<ServiceKey>,
+) {
+  for target in connection.targets {
+    // it's ok if the target manager is stopped
+    let _ = target_tx.send(TargetMessage::Unregister(target)).await;
+  }
+  for key in connection.services {
+    let _ = service_tx.send(ServiceMessage::Unsubscribe(key)).await;
+  }
+  // instances registered by the connection are gone with it, like ephemeral instances in nacos
+  for (key, instance) in connection.instances {
+    np.deregister(Owner::Connection(connection.id.clone()), &key, &instance);
+    service_changed_tx.send(key).ok();
+  }
+}
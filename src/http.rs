@@ -1,16 +1,26 @@
 mod constant;
+pub mod naming;
 
-use crate::config::{provider::ConfigProvider, target::Target};
+use crate::{
+  config::{
+    md5_of,
+    provider::ConfigProvider,
+    target::{Target, TargetMessage},
+    Config,
+  },
+  error::ProviderError,
+};
 use axum::{
   body::Body,
   extract::Query,
-  http::{HeaderMap, Request, StatusCode},
+  http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Request, StatusCode},
+  response::IntoResponse,
   routing::{any, get, post},
   Form, Router,
 };
 use constant::{
-  CONFIG_NOT_FOUND_2, DATA_ID_NOT_FOUND_1, DATA_ID_NOT_FOUND_2, GROUP_NOT_FOUND_1,
-  GROUP_NOT_FOUND_2,
+  CONFIG_NOT_FOUND_1, CONFIG_NOT_FOUND_2, CONTENT_NOT_FOUND_1, DATA_ID_NOT_FOUND_1,
+  DATA_ID_NOT_FOUND_2, GROUP_NOT_FOUND_1, GROUP_NOT_FOUND_2,
 };
 use futures::future::join_all;
 use lambda_extension::tracing::{debug, error, warn};
@@ -19,7 +29,10 @@ use serde_json::json;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
   net::TcpListener,
-  sync::{broadcast, mpsc, Mutex},
+  sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+  },
   time::sleep,
 };
 use urlencoding::encode;
@@ -30,29 +43,40 @@ struct ListeningConfig {
   targets: String,
 }
 
+/// `batch` is the time to wait for more changed targets before responding to a long-polling request.
+/// `naming` is the routes of the naming api, see [`naming::router`].
 pub fn spawn(
   listener: TcpListener,
-  target_tx: mpsc::Sender<(Target, String)>,
+  target_tx: mpsc::Sender<TargetMessage>,
   config_tx: broadcast::Sender<Target>,
   cp: impl ConfigProvider + 'static,
+  batch: Duration,
+  naming: Router,
 ) {
-  tokio::spawn(start(listener, target_tx, config_tx, cp));
+  tokio::spawn(start(listener, target_tx, config_tx, cp, batch, naming));
 }
 
 async fn start(
   listener: TcpListener,
-  target_tx: mpsc::Sender<(Target, String)>,
+  target_tx: mpsc::Sender<TargetMessage>,
   config_tx: broadcast::Sender<Target>,
   cp: impl ConfigProvider + 'static,
+  batch: Duration,
+  naming: Router,
 ) {
   macro_rules! handle_get_config {
-    ($data_id:expr, $group:expr, $tenant:expr, $cp:expr) => {{
-      $cp.get($data_id, $group, $tenant, false).await.map_err(|e|{
+    ($data_id:expr, $group:expr, $tenant:expr, $tag:expr, $cp:expr) => {{
+      $cp.get($data_id, $group, $tenant, $tag, false).await.inspect_err(|e|{
         let data_id = $data_id;
         let group = $group;
         let tenant = $tenant;
-        error!(data_id, group, tenant, error = %e.to_string(), "failed to get config");
-      }).ok()
+        let tag = $tag;
+        if let ProviderError::NotFound = e {
+          debug!(data_id, group, tenant, tag, "config not found");
+        } else {
+          error!(data_id, group, tenant, tag, error = %e.to_string(), "failed to get config");
+        }
+      })
     }};
   }
 
@@ -66,19 +90,88 @@ async fn start(
             return (
               StatusCode::INTERNAL_SERVER_ERROR,
               DATA_ID_NOT_FOUND_1.to_string(),
-            );
+            )
+              .into_response();
           };
           let Some(group) = get_non_empty(&params, "group") else {
             return (
               StatusCode::INTERNAL_SERVER_ERROR,
               GROUP_NOT_FOUND_1.to_string(),
-            );
+            )
+              .into_response();
           };
           let tenant = get_non_empty(&params, "tenant").map(|s| s.as_str());
+          let tag = get_non_empty(&params, "tag").map(|s| s.as_str());
+
+          match handle_get_config!(data_id, group, tenant, tag, cp) {
+            Ok(config) => (
+              StatusCode::OK,
+              config_headers(&config),
+              [(CONTENT_TYPE, config.mime())],
+              config.content().to_string(),
+            )
+              .into_response(),
+            Err(e) => error_response_1(&e).into_response(),
+          }
+        }
+      })
+      .post({
+        let cp = cp.clone();
+        let target_tx = target_tx.clone();
+        // params can be sent in the query string or the form body
+        move |Query(mut params): Query<HashMap<String, String>>,
+              form: Option<Form<HashMap<String, String>>>| async move {
+          params.extend(form.map(|Form(form)| form).unwrap_or_default());
+          let target = match target_of(&params) {
+            Ok(target) => target,
+            Err(response) => return response,
+          };
+          let Some(content) = params.remove("content") else {
+            return (StatusCode::BAD_REQUEST, CONTENT_NOT_FOUND_1.to_string());
+          };
+          let content_type = get_non_empty(&params, "type").map(|s| s.as_str());
+          let cas_md5 = get_non_empty(&params, "casMd5").map(|s| s.as_str());
 
-          match handle_get_config!(data_id, group, tenant, cp) {
-            Some(config) => (StatusCode::OK, config.content().to_string()),
-            None => (StatusCode::NOT_FOUND, "Not Found".to_string()),
+          debug!(?target, content_type, cas_md5, "publish config");
+          match cp.publish(&target, content, content_type, cas_md5).await {
+            Ok(config) => {
+              let md5 = config.md5().to_owned();
+              target_tx
+                .send(TargetMessage::Changed(target, md5))
+                .await
+                .unwrap();
+              (StatusCode::OK, "true".to_string())
+            }
+            Err(e) => {
+              warn!(?target, error = %e, "failed to publish config");
+              error_response_1(&e)
+            }
+          }
+        }
+      })
+      .delete({
+        let cp = cp.clone();
+        let target_tx = target_tx.clone();
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let target = match target_of(&params) {
+            Ok(target) => target,
+            Err(response) => return response,
+          };
+
+          debug!(?target, "remove config");
+          match cp.remove(&target).await {
+            Ok(()) => {
+              // the md5 of a removed config is empty
+              target_tx
+                .send(TargetMessage::Changed(target, String::new()))
+                .await
+                .unwrap();
+              (StatusCode::OK, "true".to_string())
+            }
+            Err(e) => {
+              warn!(?target, error = %e, "failed to remove config");
+              error_response_1(&e)
+            }
           }
         }
       }),
@@ -89,26 +182,27 @@ async fn start(
         let mut cp = cp.clone();
         move |Query(params): Query<HashMap<String, String>>| async move {
           let Some(data_id) = get_non_empty(&params, "dataId") else {
-            return (StatusCode::BAD_REQUEST, DATA_ID_NOT_FOUND_2.to_string());
+            return (StatusCode::BAD_REQUEST, DATA_ID_NOT_FOUND_2.to_string()).into_response();
           };
           let Some(group) = get_non_empty(&params, "group") else {
-            return (StatusCode::BAD_REQUEST, GROUP_NOT_FOUND_2.to_string());
+            return (StatusCode::BAD_REQUEST, GROUP_NOT_FOUND_2.to_string()).into_response();
           };
           let tenant = get_non_empty(&params, "namespaceId").map(|s| s as &str);
+          let tag = get_non_empty(&params, "tag").map(|s| s as &str);
 
-          // TODO: "tag" in nacos api v2 is not supported yet
-
-          match handle_get_config!(data_id, group, tenant, cp) {
-            Some(config) => (
+          match handle_get_config!(data_id, group, tenant, tag, cp) {
+            Ok(config) => (
               StatusCode::OK,
+              config_headers(&config),
               json!({
                 "code": 0,
                 "message": "success",
                 "data": config.content()
               })
               .to_string(),
-            ),
-            None => (StatusCode::NOT_FOUND, CONFIG_NOT_FOUND_2.to_string()),
+            )
+              .into_response(),
+            Err(e) => error_response_2(&e).into_response(),
           }
         }
       }),
@@ -122,64 +216,29 @@ async fn start(
           let mut config_rx = config_tx.subscribe();
 
           async move {
-            if targets.is_empty() {
+            let Some(listening) = parse_listening_configs(&targets) else {
               return (
                 StatusCode::BAD_REQUEST,
                 "caused: invalid probeModify;".to_string(),
               );
-            }
+            };
 
-            let update_now = Arc::new(Mutex::new(vec![]));
-            join_all(targets.split('\x01').filter(|s| !s.is_empty()).map(|s| {
-              let mut parts = s.split('\x02');
-              // TODO: better error handling
-              let data_id = parts.next().unwrap();
-              let group = parts.next().unwrap();
-              let md5 = parts.next().unwrap();
-              let tenant = parts.next();
-              let target = Target {
-                data_id: data_id.to_string().into(),
-                group: group.to_string().into(),
-                tenant: tenant.map(|s| s.to_string().into()),
-              };
-
-              let mut cp = cp.clone();
-              let target_tx = target_tx.clone();
-              let update_now = update_now.clone();
-
-              async move {
-                // register target to the target manager
-                target_tx
-                  .send((target.clone(), md5.to_owned()))
-                  .await
-                  .unwrap();
-                // check if the md5 mismatch now
-                if let Ok(cached) = cp
-                  .get(&target.data_id, &target.group, target.tenant(), false)
-                  .await
-                {
-                  let cached_md5 = cached.md5();
-                  if md5 != cached_md5 {
-                    debug!(md5, cached_md5, "md5 not match");
-                    update_now.lock().await.push(target);
-                  }
-                }
-              }
-            }))
-            .await;
+            // register targets to the target manager,
+            // and hold them until this request is done
+            for (target, md5) in &listening {
+              target_tx
+                .send(TargetMessage::Register(target.clone(), md5.clone()))
+                .await
+                .unwrap();
+            }
+            let _holding =
+              Holding::new(target_tx.clone(), listening.keys().cloned().collect()).await;
 
-            let update_now = Arc::try_unwrap(update_now).unwrap().into_inner();
+            // check if the md5 mismatch now
+            let update_now = changed_targets(&cp, &listening).await;
             if !update_now.is_empty() {
-              let res = encode(
-                &update_now
-                  .iter()
-                  .map(|t| t.to_param_string())
-                  .collect::<Vec<_>>()
-                  .join(""),
-              )
-              .to_string();
-              debug!(res, "immediate update");
-              return (StatusCode::OK, res);
+              debug!(count = update_now.len(), "immediate update");
+              return (StatusCode::OK, to_response(&update_now));
             }
 
             let timeout = headers
@@ -190,28 +249,62 @@ async fn start(
             let timeout = sleep(Duration::from_millis(timeout));
             tokio::pin!(timeout);
 
-            loop {
+            // wait for the first change of the listening targets
+            let mut changed = loop {
               tokio::select! {
                 _ = &mut timeout => {
                   // timeout, nothing is changed
                   debug!("listener timeout");
                   return (StatusCode::OK, "".to_string())
                 }
-                res = config_rx.recv() => {
+                res = config_rx.recv() => match res {
                   // got config update from the target manager
-                  // TODO: check if the updated config is one of we are listening
-                  if let Ok(target) = res {
-                    let res = encode(&target.to_param_string()).to_string();
-                    debug!(res, "update");
-                    return (StatusCode::OK, res);
+                  Ok(target) => if listening.contains_key(&target) {
+                    break vec![target];
+                  }
+                  Err(RecvError::Lagged(n)) => {
+                    // some updates are missed, check all listening targets
+                    warn!(n, "listener lagged behind config changes");
+                    let changed = changed_targets(&cp, &listening).await;
+                    if !changed.is_empty() {
+                      break changed;
+                    }
+                  }
+                  Err(RecvError::Closed) => return (StatusCode::OK, "".to_string()),
+                }
+              }
+            };
+
+            // other targets might be changed in the same refresh, batch them into one response
+            let batch = sleep(batch);
+            tokio::pin!(batch);
+            loop {
+              tokio::select! {
+                _ = &mut batch => break,
+                _ = &mut timeout => break,
+                res = config_rx.recv() => match res {
+                  Ok(target) => if listening.contains_key(&target) && !changed.contains(&target) {
+                    changed.push(target);
                   }
+                  Err(RecvError::Lagged(_)) => {
+                    for target in changed_targets(&cp, &listening).await {
+                      if !changed.contains(&target) {
+                        changed.push(target);
+                      }
+                    }
+                  }
+                  Err(RecvError::Closed) => break,
                 }
               }
             }
+
+            debug!(count = changed.len(), "update");
+            (StatusCode::OK, to_response(&changed))
           }
         },
       ),
     )
+    .merge(naming)
     .fallback(any(|request: Request<Body>| async move {
       warn!(uri = %request.uri().to_string(), "unhandled request");
       (StatusCode::NOT_FOUND, "Not Found".to_string())
@@ -220,6 +313,229 @@ async fn start(
   axum::serve(listener, app).await.unwrap();
 }
 
+/// Hold targets in the target manager until dropped,
+/// so the targets won't expire while the long-polling request is pending.
+/// The request might be dropped at any await point if the client disconnects,
+/// so the targets are released in [`Drop`].
+struct Holding {
+  target_tx: mpsc::Sender<TargetMessage>,
+  targets: Vec<Target>,
+}
+
+impl Holding {
+  async fn new(target_tx: mpsc::Sender<TargetMessage>, targets: Vec<Target>) -> Self {
+    for target in &targets {
+      target_tx
+        .send(TargetMessage::Hold(target.clone()))
+        .await
+        .unwrap();
+    }
+    Holding { target_tx, targets }
+  }
+}
+
+impl Drop for Holding {
+  fn drop(&mut self) {
+    let target_tx = self.target_tx.clone();
+    let targets = std::mem::take(&mut self.targets);
+    tokio::spawn(async move {
+      for target in targets {
+        // it's ok if the target manager is stopped
+        let _ = target_tx.send(TargetMessage::Release(target)).await;
+      }
+    });
+  }
+}
+
+/// Parse `"{data_id}\x02{group}\x02{md5}[\x02{tenant}]\x01..."` into targets and their md5.
+/// Return `None` if the input is empty or malformed.
+fn parse_listening_configs(targets: &str) -> Option<HashMap<Target, String>> {
+  let listening = targets
+    .split('\x01')
+    .filter(|s| !s.is_empty())
+    .map(|s| {
+      let mut parts = s.split('\x02');
+      let data_id = parts.next().filter(|s| !s.is_empty())?;
+      let group = parts.next().filter(|s| !s.is_empty())?;
+      let md5 = parts.next()?;
+      let tenant = parts.next().filter(|s| !s.is_empty());
+      let target = Target {
+        data_id: data_id.to_string().into(),
+        group: group.to_string().into(),
+        tenant: tenant.map(|s| s.to_string().into()),
+        tag: None,
+      };
+      Some((target, md5.to_string()))
+    })
+    .collect::<Option<HashMap<_, _>>>()?;
+
+  (!listening.is_empty()).then_some(listening)
+}
+
+/// Return targets whose cached md5 mismatches the client md5.
+async fn changed_targets(
+  cp: &(impl ConfigProvider + 'static),
+  listening: &HashMap<Target, String>,
+) -> Vec<Target> {
+  join_all(listening.iter().map(|(target, md5)| {
+    let mut cp = cp.clone();
+    async move {
+      let cached = cp
+        .get(
+          &target.data_id,
+          &target.group,
+          target.tenant(),
+          target.tag(),
+          false,
+        )
+        .await;
+      let cached_md5 = md5_of(&cached)?;
+      (md5 != cached_md5).then(|| {
+        debug!(md5, cached_md5, "md5 not match");
+        target.clone()
+      })
+    }
+  }))
+  .await
+  .into_iter()
+  .flatten()
+  .collect()
+}
+
+/// Encode changed targets as the response of a long-polling request.
+fn to_response(targets: &[Target]) -> String {
+  encode(
+    &targets
+      .iter()
+      .map(|t| t.to_param_string())
+      .collect::<Vec<_>>()
+      .join(""),
+  )
+  .to_string()
+}
+
+/// Map provider errors to responses like nacos api v1.
+fn error_response_1(e: &ProviderError) -> (StatusCode, String) {
+  match e {
+    ProviderError::NotFound => (StatusCode::NOT_FOUND, CONFIG_NOT_FOUND_1.to_string()),
+    ProviderError::Unauthorized(msg) => (StatusCode::FORBIDDEN, msg.clone()),
+    e => (StatusCode::INTERNAL_SERVER_ERROR, format!("caused: {};", e)),
+  }
+}
+
+/// Map provider errors to responses like nacos api v2.
+fn error_response_2(e: &ProviderError) -> (StatusCode, String) {
+  match e {
+    ProviderError::NotFound => (StatusCode::NOT_FOUND, CONFIG_NOT_FOUND_2.to_string()),
+    ProviderError::Unauthorized(msg) => (
+      StatusCode::FORBIDDEN,
+      json!({
+        "code": 10001,
+        "message": "access denied",
+        "data": msg
+      })
+      .to_string(),
+    ),
+    e => (
+      StatusCode::INTERNAL_SERVER_ERROR,
+      json!({
+        "code": 30000,
+        "message": "server error",
+        "data": e.to_string()
+      })
+      .to_string(),
+    ),
+  }
+}
+
+/// Headers describing the config, like nacos does,
+/// plus the version of the config if known, e.g. the commit in git mode.
+fn config_headers(config: &Config) -> HeaderMap {
+  let mut headers = HeaderMap::new();
+  if let Ok(content_type) = HeaderValue::from_str(config.content_type()) {
+    headers.insert("Config-Type", content_type);
+  }
+  headers.insert("Last-Modified", HeaderValue::from(config.last_modified()));
+  if let Some(Ok(version)) = config.version().map(|v| HeaderValue::from_str(v)) {
+    headers.insert("Config-Version", version);
+  }
+  // like nacos, clients know the config is a gray release
+  if config.beta() {
+    headers.insert("isBeta", HeaderValue::from_static("true"));
+  }
+  headers
+}
+
+/// Parse the target of a write request like nacos api v1.
+fn target_of(params: &HashMap<String, String>) -> Result<Target, (StatusCode, String)> {
+  let Some(data_id) = get_non_empty(params, "dataId") else {
+    return Err((StatusCode::BAD_REQUEST, DATA_ID_NOT_FOUND_1.to_string()));
+  };
+  let Some(group) = get_non_empty(params, "group") else {
+    return Err((StatusCode::BAD_REQUEST, GROUP_NOT_FOUND_1.to_string()));
+  };
+  Ok(Target {
+    data_id: Arc::new(data_id.clone()),
+    group: Arc::new(group.clone()),
+    tenant: get_non_empty(params, "tenant").map(|s| Arc::new(s.clone())),
+    tag: get_non_empty(params, "tag").map(|s| Arc::new(s.clone())),
+  })
+}
+
 fn get_non_empty<'a>(params: &'a HashMap<String, String>, key: &str) -> Option<&'a String> {
   params.get(key).filter(|s| !s.is_empty())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn target(data_id: &str, group: &str, tenant: Option<&str>) -> Target {
+    Target {
+      data_id: Arc::new(data_id.to_string()),
+      group: Arc::new(group.to_string()),
+      tenant: tenant.map(|t| Arc::new(t.to_string())),
+      tag: None,
+    }
+  }
+
+  #[test]
+  fn parse_listening_configs_with_and_without_tenant() {
+    let listening =
+      parse_listening_configs("a\x02DEFAULT_GROUP\x02md5a\x01b\x02g\x02\x02ns\x01").unwrap();
+    assert_eq!(listening.len(), 2);
+    assert_eq!(listening[&target("a", "DEFAULT_GROUP", None)], "md5a");
+    // an empty md5 means the client doesn't have the config
+    assert_eq!(listening[&target("b", "g", Some("ns"))], "");
+  }
+
+  #[test]
+  fn parse_listening_configs_empty_tenant() {
+    let listening = parse_listening_configs("a\x02g\x02md5\x02\x01").unwrap();
+    assert_eq!(listening[&target("a", "g", None)], "md5");
+  }
+
+  #[test]
+  fn parse_listening_configs_round_trip() {
+    let target = target("a", "g", Some("ns"));
+    // the response lists changed targets in the same separators
+    assert_eq!(target.to_param_string(), "a\x02g\x02ns\x01");
+    let listening = parse_listening_configs("a\x02g\x02md5\x02ns\x01").unwrap();
+    assert!(listening.contains_key(&target));
+  }
+
+  #[test]
+  fn parse_listening_configs_malformed() {
+    assert!(parse_listening_configs("").is_none());
+    assert!(parse_listening_configs("\x01\x01").is_none());
+    // missing md5
+    assert!(parse_listening_configs("a\x02g\x01").is_none());
+    // empty data id or group
+    assert!(parse_listening_configs("\x02g\x02md5\x01").is_none());
+    assert!(parse_listening_configs("a\x02\x02md5\x01").is_none());
+    // one malformed config fails the whole request
+    assert!(parse_listening_configs("a\x02g\x02md5\x01b\x01").is_none());
+    // not separated by \x02
+    assert!(parse_listening_configs("a,g,md5").is_none());
+  }
+}
@@ -8,3 +8,9 @@ pub const GROUP_NOT_FOUND_2: &str =
   r#"{"code":10000,"message":"parameter missing","data":"Required request parameter 'group' for method parameter type String is not present"}"#;
 pub const CONFIG_NOT_FOUND_2: &str =
   r#"{"code":20004,"message":"resource not found","data":"config data not exist"}"#;
+pub const CONFIG_NOT_FOUND_1: &str = "config data not exist\n";
+pub const SERVICE_NAME_NOT_FOUND_1: &str =
+  "caused: Required request parameter &#39;serviceName&#39; for method parameter type String is not present;";
+pub const INSTANCE_INVALID_1: &str = "caused: serviceName, ip and port are required;";
+pub const CONTENT_NOT_FOUND_1: &str =
+  "caused: Required request parameter &#39;content&#39; for method parameter type String is not present;";
@@ -0,0 +1,247 @@
+use super::{
+  constant::{INSTANCE_INVALID_1, SERVICE_NAME_NOT_FOUND_1},
+  error_response_1, get_non_empty,
+};
+use crate::naming::{
+  now_millis, provider::NamingProvider, subscription::ServiceMessage, Instance, Owner, ServiceKey,
+};
+use axum::{
+  extract::Query,
+  http::StatusCode,
+  routing::{get, post, put},
+  Router,
+};
+use lambda_extension::tracing::{debug, error};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+
+const DEFAULT_GROUP: &str = "DEFAULT_GROUP";
+
+/// Routes of the nacos naming api v1.
+pub fn router(
+  np: impl NamingProvider + 'static,
+  service_tx: mpsc::Sender<ServiceMessage>,
+  changed_tx: broadcast::Sender<ServiceKey>,
+) -> Router {
+  Router::new()
+    .route(
+      "/nacos/v1/ns/instance/list",
+      get({
+        let np = np.clone();
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let Some(key) = service_key(&params) else {
+            return (
+              StatusCode::BAD_REQUEST,
+              SERVICE_NAME_NOT_FOUND_1.to_string(),
+            );
+          };
+          let clusters: Vec<_> = get_non_empty(&params, "clusters")
+            .map(|s| s.split(',').collect())
+            .unwrap_or_default();
+          let healthy_only = params.get("healthyOnly").is_some_and(|s| s == "true");
+          debug!(?key, ?clusters, healthy_only, "list instances");
+
+          let service = match np.clone().get(&key, false).await {
+            Ok(service) => service,
+            Err(e) => {
+              error!(?key, error = %e, "failed to get service");
+              return error_response_1(&e);
+            }
+          };
+          // refresh the service along with configs, so the cache won't be stale
+          service_tx
+            .send(ServiceMessage::Register(
+              key.clone(),
+              service.checksum().to_owned(),
+            ))
+            .await
+            .unwrap();
+
+          let hosts: Vec<_> = service
+            .select(&clusters, healthy_only)
+            .map(|instance| to_host(&key, instance))
+            .collect();
+          let body = json!({
+            "name": key.grouped_name(),
+            "groupName": key.group.as_str(),
+            "clusters": clusters.join(","),
+            "cacheMillis": 10000,
+            "hosts": hosts,
+            "lastRefTime": now_millis(),
+            "checksum": service.checksum(),
+            "allIPs": false,
+            "reachProtectionThreshold": false,
+            "valid": true,
+          });
+          (StatusCode::OK, body.to_string())
+        }
+      }),
+    )
+    .route(
+      "/nacos/v1/ns/instance",
+      post({
+        let np = np.clone();
+        let changed_tx = changed_tx.clone();
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let (Some(key), Some(instance)) = (service_key(&params), to_instance(&params)) else {
+            return (StatusCode::BAD_REQUEST, INSTANCE_INVALID_1.to_string());
+          };
+          debug!(?key, ?instance, "register instance");
+          np.register(Owner::Http, &key, instance);
+          // it's ok if no bi-stream is connected
+          changed_tx.send(key).ok();
+          (StatusCode::OK, "ok".to_string())
+        }
+      })
+      .delete({
+        let np = np.clone();
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let (Some(key), Some(instance)) = (service_key(&params), to_instance(&params)) else {
+            return (StatusCode::BAD_REQUEST, INSTANCE_INVALID_1.to_string());
+          };
+          debug!(?key, ?instance, "deregister instance");
+          np.deregister(Owner::Http, &key, &instance);
+          changed_tx.send(key).ok();
+          (StatusCode::OK, "ok".to_string())
+        }
+      }),
+    )
+    .route(
+      "/nacos/v1/ns/instance/beat",
+      put({
+        let np = np.clone();
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let (Some(key), Some(instance)) = (service_key(&params), beat_instance(&params)) else {
+            return (StatusCode::BAD_REQUEST, INSTANCE_INVALID_1.to_string());
+          };
+          // like nacos, the client registers the instance again if it's not found
+          let code = if np.beat(Owner::Http, &key, &instance) {
+            10200
+          } else {
+            debug!(?key, ?instance, "beat of an unknown instance");
+            20404
+          };
+          let body = json!({
+            "clientBeatInterval": 5000,
+            "code": code,
+            "lightBeatEnabled": true,
+          });
+          (StatusCode::OK, body.to_string())
+        }
+      }),
+    )
+    .route(
+      "/nacos/v1/ns/service/list",
+      get(
+        move |Query(params): Query<HashMap<String, String>>| async move {
+          let namespace = get_non_empty(&params, "namespaceId").map(|s| s.as_str());
+          let group = get_non_empty(&params, "groupName").map_or(DEFAULT_GROUP, |s| s.as_str());
+          let page_no: usize = params
+            .get("pageNo")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1);
+          let page_size: usize = params
+            .get("pageSize")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(10);
+
+          match np.clone().list(namespace, group).await {
+            Ok(names) => {
+              let doms: Vec<_> = names
+                .iter()
+                .skip(page_no.saturating_sub(1) * page_size)
+                .take(page_size)
+                .collect();
+              let body = json!({ "count": names.len(), "doms": doms });
+              (StatusCode::OK, body.to_string())
+            }
+            Err(e) => {
+              error!(namespace, group, error = %e, "failed to list services");
+              error_response_1(&e)
+            }
+          }
+        },
+      ),
+    )
+    .route(
+      "/nacos/v1/ns/operator/metrics",
+      get(|| async { json!({ "status": "UP" }).to_string() }),
+    )
+}
+
+fn service_key(params: &HashMap<String, String>) -> Option<ServiceKey> {
+  let name = get_non_empty(params, "serviceName")?;
+  let group = get_non_empty(params, "groupName").map_or(DEFAULT_GROUP, |s| s.as_str());
+  let namespace = get_non_empty(params, "namespaceId").map(|s| s.as_str());
+  Some(ServiceKey::new(namespace, group, name))
+}
+
+/// Parse an instance from the parameters of a registration, like nacos api v1.
+fn to_instance(params: &HashMap<String, String>) -> Option<Instance> {
+  let flag = |key: &str| params.get(key).is_none_or(|s| s != "false");
+  Some(Instance {
+    ip: get_non_empty(params, "ip")?.clone(),
+    port: params.get("port")?.parse().ok()?,
+    weight: params
+      .get("weight")
+      .and_then(|s| s.parse().ok())
+      .unwrap_or(1.0),
+    healthy: flag("healthy"),
+    enabled: flag("enable") && flag("enabled"),
+    ephemeral: flag("ephemeral"),
+    cluster_name: get_non_empty(params, "clusterName")
+      .cloned()
+      .unwrap_or_else(|| "DEFAULT".to_string()),
+    metadata: params
+      .get("metadata")
+      .and_then(|s| serde_json::from_str(s).ok())
+      .unwrap_or_default(),
+  })
+}
+
+/// Parse the instance of a beat from the parameters, or the `beat` json sent by older clients.
+/// Only the address and the cluster are used to identify the instance.
+fn beat_instance(params: &HashMap<String, String>) -> Option<Instance> {
+  let beat: Value = params
+    .get("beat")
+    .and_then(|s| serde_json::from_str(s).ok())
+    .unwrap_or_default();
+  Some(Instance {
+    ip: get_non_empty(params, "ip")
+      .cloned()
+      .or_else(|| beat["ip"].as_str().map(str::to_string))?,
+    port: params
+      .get("port")
+      .and_then(|s| s.parse().ok())
+      .or_else(|| beat["port"].as_u64().and_then(|p| p.try_into().ok()))?,
+    weight: 1.0,
+    healthy: true,
+    enabled: true,
+    ephemeral: true,
+    cluster_name: get_non_empty(params, "clusterName")
+      .cloned()
+      .or_else(|| beat["cluster"].as_str().map(str::to_string))
+      .unwrap_or_else(|| "DEFAULT".to_string()),
+    metadata: HashMap::new(),
+  })
+}
+
+/// Encode an instance like nacos api v1.
+fn to_host(key: &ServiceKey, instance: &Instance) -> Value {
+  json!({
+    "instanceId": instance.id(key),
+    "ip": instance.ip,
+    "port": instance.port,
+    "weight": instance.weight,
+    "healthy": instance.healthy,
+    "enabled": instance.enabled,
+    "ephemeral": instance.ephemeral,
+    "clusterName": instance.cluster_name,
+    "serviceName": key.grouped_name(),
+    "metadata": instance.metadata,
+    "instanceHeartBeatInterval": 5000,
+    "instanceHeartBeatTimeOut": 15000,
+    "ipDeleteTimeout": 30000,
+  })
+}
@@ -1,11 +1,41 @@
+mod aws;
 mod config;
+mod error;
 mod grpc;
 mod http;
+mod naming;
+mod origin;
 
-use crate::config::{fs::FsConfigProvider, passthrough::PassthroughConfigProvider};
+use crate::{
+  aws::{JsonClient, Signer},
+  config::{
+    chain::{AnyConfigProvider, ChainConfigProvider, ChainPolicy},
+    fs::{watch::spawn_watcher, ChangeDetection, FsConfigProvider},
+    git::GitConfigProvider,
+    gray::{GrayConfigProvider, Sandbox},
+    local::LocalConfigProvider,
+    passthrough::PassthroughConfigProvider,
+    s3::S3ConfigProvider,
+    secrets::SecretsConfigProvider,
+    ssm::SsmConfigProvider,
+  },
+  naming::{
+    fs::FsNamingProvider, local::LocalNamingProvider, passthrough::PassthroughNamingProvider,
+    provider::NamingProvider, subscription::spawn_subscription_manager,
+  },
+  origin::{
+    auth::{Auth, Credentials},
+    server_list::{Selection, ServerList},
+    Origin,
+  },
+};
 use anyhow::Result;
 use aws_lambda_runtime_proxy::{LambdaRuntimeApiClient, MockLambdaRuntimeApiServer};
-use config::{provider::ConfigProvider, target::spawn_target_manager};
+use config::{
+  persist::{self, spawn_persister},
+  provider::ConfigProvider,
+  target::{prefetch, spawn_target_manager, RefreshEvent, Target, TargetManagerOptions},
+};
 use lambda_extension::{
   service_fn,
   tracing::{debug, subscriber::EnvFilter, warn},
@@ -16,11 +46,12 @@ use std::{
   fmt::Display,
   net::{Ipv4Addr, SocketAddrV4},
   str::FromStr,
+  sync::Arc,
   time::Duration,
 };
 use tokio::{
   net::TcpListener,
-  sync::{mpsc, watch},
+  sync::{mpsc, oneshot, watch},
   time::{sleep, Instant},
 };
 use tracing_subscriber::filter::LevelFilter;
@@ -43,20 +74,115 @@ async fn main() -> Result<(), Error> {
   let sync_port = parse_env("AWS_LAMBDA_NACOS_ADAPTER_SYNC_PORT", 0);
   let sync_cooldown_ms = parse_env("AWS_LAMBDA_NACOS_ADAPTER_SYNC_COOLDOWN_MS", 0);
   let sync_wait_ms = parse_env("AWS_LAMBDA_NACOS_ADAPTER_SYNC_WAIT_MS", 0);
+  let listener_batch_ms = parse_env("AWS_LAMBDA_NACOS_ADAPTER_LISTENER_BATCH_MS", 10);
+  let target_manager_options = TargetManagerOptions {
+    ttl: parse_env_ms("AWS_LAMBDA_NACOS_ADAPTER_TARGET_TTL_MS", 600000),
+    concurrency: parse_env("AWS_LAMBDA_NACOS_ADAPTER_REFRESH_CONCURRENCY", 16),
+    deadline: parse_env_ms("AWS_LAMBDA_NACOS_ADAPTER_REFRESH_DEADLINE_MS", 0),
+    timeout: parse_env_ms("AWS_LAMBDA_NACOS_ADAPTER_REFRESH_TIMEOUT_MS", 0),
+  };
+  let persist_path = env::var("AWS_LAMBDA_NACOS_ADAPTER_PERSIST_PATH")
+    .unwrap_or_else(|_| "/tmp/aws-lambda-nacos-adapter-cache.json".to_string());
+  debug!("AWS_LAMBDA_NACOS_ADAPTER_PERSIST_PATH={}", persist_path);
+  // an empty path disables the persistence
+  let persist_path = (!persist_path.is_empty()).then_some(persist_path);
+  let persist_interval = parse_env_ms("AWS_LAMBDA_NACOS_ADAPTER_PERSIST_INTERVAL_MS", 60000);
+  let mut options = MockNacosOptions {
+    port,
+    listener_batch_ms,
+    target_manager_options,
+    persist_path,
+    persist_interval,
+    prefetch_targets: prefetch_targets(),
+    watch_path: None,
+    watch_debounce: Duration::from_millis(parse_env(
+      "AWS_LAMBDA_NACOS_ADAPTER_WATCH_DEBOUNCE_MS",
+      100,
+    )),
+  };
+
+  // gray release is disabled unless the rules data id is specified
+  let gray_rules = env::var("AWS_LAMBDA_NACOS_ADAPTER_GRAY_DATA_ID")
+    .ok()
+    .filter(|data_id| !data_id.is_empty())
+    .map(|data_id| {
+      let group = env::var("AWS_LAMBDA_NACOS_ADAPTER_GRAY_GROUP")
+        .unwrap_or_else(|_| "DEFAULT_GROUP".to_string());
+      debug!("gray rules: dataId={}, group={}", data_id, group);
+      (data_id, group)
+    });
+  let sandbox = Sandbox::from_env();
 
   if sync_cooldown_ms < cooldown_ms {
     warn!("AWS_LAMBDA_NACOS_ADAPTER_SYNC_COOLDOWN_MS should be no less than AWS_LAMBDA_NACOS_ADAPTER_COOLDOWN_MS");
   }
 
-  // start mock nacos, try passthrough mode first, otherwise use fs mode
-  let refresh_tx = if let Ok(origin) = env::var("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ADDRESS") {
-    debug!("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ADDRESS={}", origin);
-    start_mock_nacos(port, PassthroughConfigProvider::new(cache_size, origin)).await?
+  // the origin is required by the passthrough provider and naming
+  let origin = env::var("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ADDRESS")
+    .ok()
+    .map(|origin| {
+      debug!("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ADDRESS={}", origin);
+      let credentials = origin_credentials();
+      debug!("origin credentials: {:?}", credentials);
+      let servers = ServerList::new(
+        &origin,
+        parse_env(
+          "AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_SELECTION",
+          Selection::RoundRobin,
+        ),
+        parse_env("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_MAX_FAILURES", 3),
+        Duration::from_millis(parse_env("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_EJECT_MS", 30000)),
+      );
+      Origin::new(servers, Auth::new(credentials))
+    });
+  let stale_if_error = parse_env("AWS_LAMBDA_NACOS_ADAPTER_STALE_IF_ERROR", true);
+  let providers = config_providers(cache_size, origin.as_ref(), stale_if_error, &mut options)?;
+  let policy = parse_env(
+    "AWS_LAMBDA_NACOS_ADAPTER_CHAIN_POLICY",
+    ChainPolicy::FirstFound,
+  );
+  // configs published by clients are kept locally unless explicitly forwarded to the providers
+  let mut forward_writes = parse_env("AWS_LAMBDA_NACOS_ADAPTER_FORWARD_WRITES", false);
+  if forward_writes && !providers.iter().any(AnyConfigProvider::supports_writes) {
+    warn!("no provider supports writes, AWS_LAMBDA_NACOS_ADAPTER_FORWARD_WRITES is ignored");
+    forward_writes = false;
+  }
+  let cp = LocalConfigProvider::new(
+    GrayConfigProvider::new(
+      ChainConfigProvider::new(providers, policy),
+      gray_rules,
+      sandbox.clone(),
+    ),
+    forward_writes,
+  );
+
+  // start mock nacos, naming uses the origin if specified, otherwise the file system
+  let (refresh_tx, persist_tx) = if let Some(origin) = origin {
+    start_mock_nacos(
+      cp,
+      LocalNamingProvider::new(PassthroughNamingProvider::new(
+        cache_size,
+        origin,
+        stale_if_error,
+      )),
+      options,
+    )
+    .await?
   } else {
-    let prefix = env::var("AWS_LAMBDA_NACOS_ADAPTER_CONFIG_PATH")
-      .unwrap_or_else(|_| "/mnt/efs/nacos/".to_string());
-    debug!("AWS_LAMBDA_NACOS_ADAPTER_CONFIG_PATH={}", prefix);
-    start_mock_nacos(port, FsConfigProvider::new(cache_size, prefix)).await?
+    let naming_prefix = env::var("AWS_LAMBDA_NACOS_ADAPTER_NAMING_PATH")
+      .unwrap_or_else(|_| "/mnt/efs/nacos-naming/".to_string());
+    debug!("AWS_LAMBDA_NACOS_ADAPTER_NAMING_PATH={}", naming_prefix);
+    start_mock_nacos(
+      cp,
+      LocalNamingProvider::new(
+        FsNamingProvider::new(cache_size, naming_prefix).with_change_detection(parse_env(
+          "AWS_LAMBDA_NACOS_ADAPTER_CHANGE_DETECTION",
+          ChangeDetection::Metadata,
+        )),
+      ),
+      options,
+    )
+    .await?
   };
 
   let (last_refresh_setter, last_refresh) = watch::channel(Instant::now());
@@ -113,8 +239,10 @@ async fn main() -> Result<(), Error> {
   // start lambda extension
   lambda_extension::run(service_fn(move |event: LambdaEvent| {
     let refresh_tx = refresh_tx.clone();
+    let persist_tx = persist_tx.clone();
     let last_refresh_setter = last_refresh_setter.clone();
     let last_refresh = last_refresh.clone();
+    let sandbox = sandbox.clone();
 
     async move {
       match event.next {
@@ -122,8 +250,17 @@ async fn main() -> Result<(), Error> {
           // TODO: print nacos client logs?
           // user should provide a file path like /tmp/nacos/logs/nacos/config.log
           // based on `-DJM.LOG.PATH`
+
+          if let Some(persist_tx) = persist_tx {
+            let (done_tx, done_rx) = oneshot::channel();
+            persist_tx.send(done_tx).await?;
+            done_rx.await?;
+          }
         }
-        NextEvent::Invoke(_e) => {
+        NextEvent::Invoke(e) => {
+          // gray rules may depend on the alias
+          sandbox.set_alias_from_arn(&e.invoked_function_arn);
+
           let last_refresh = last_refresh.borrow();
 
           if sync_port != 0 && last_refresh.elapsed().as_millis() >= sync_cooldown_ms {
@@ -155,22 +292,248 @@ async fn main() -> Result<(), Error> {
   .await
 }
 
-async fn start_mock_nacos(
+struct MockNacosOptions {
   port: u16,
+  listener_batch_ms: u64,
+  target_manager_options: TargetManagerOptions,
+  persist_path: Option<String>,
+  persist_interval: Option<Duration>,
+  prefetch_targets: Vec<Target>,
+  /// The config directory to watch in fs mode.
+  watch_path: Option<String>,
+  watch_debounce: Duration,
+}
+
+/// Return the refresh sender and the persist sender (if the persistence is enabled).
+async fn start_mock_nacos(
   cp: impl ConfigProvider + 'static,
-) -> Result<mpsc::Sender<mpsc::Sender<()>>, Error> {
-  let (refresh_tx, refresh_rx) = mpsc::channel(1);
-  let (target_tx, config_tx) = spawn_target_manager(cp.clone(), refresh_rx);
+  np: impl NamingProvider + 'static,
+  options: MockNacosOptions,
+) -> Result<
+  (
+    mpsc::Sender<mpsc::Sender<RefreshEvent>>,
+    Option<mpsc::Sender<oneshot::Sender<()>>>,
+  ),
+  Error,
+> {
+  // warm the cache before serving
+  let persist_tx = match options.persist_path {
+    Some(path) => {
+      persist::restore(&cp, &path).await;
+      Some(spawn_persister(cp.clone(), path, options.persist_interval))
+    }
+    None => None,
+  };
+
+  // configs and services are refreshed together,
+  // the refresh is done when both managers drop the `changed_tx`
+  let (refresh_tx, mut refresh_rx) = mpsc::channel::<mpsc::Sender<RefreshEvent>>(1);
+  let (config_refresh_tx, config_refresh_rx) = mpsc::channel(1);
+  let (naming_refresh_tx, naming_refresh_rx) = mpsc::channel(1);
+  tokio::spawn(async move {
+    while let Some(changed_tx) = refresh_rx.recv().await {
+      if config_refresh_tx.send(changed_tx.clone()).await.is_err()
+        || naming_refresh_tx.send(changed_tx).await.is_err()
+      {
+        break;
+      }
+    }
+  });
+  let (target_tx, config_tx) = spawn_target_manager(
+    cp.clone(),
+    config_refresh_rx,
+    options.target_manager_options,
+  );
+  let (service_tx, service_changed_tx) = spawn_subscription_manager(
+    np.clone(),
+    naming_refresh_rx,
+    options.target_manager_options,
+  );
+
+  // this is done before registering the extension, so it won't compete with the function's init
+  prefetch(options.prefetch_targets, &target_tx).await;
+  // file changes only refresh known targets, so start watching once the prefetched targets are known
+  if let Some(path) = &options.watch_path {
+    spawn_watcher(path, options.watch_debounce, target_tx.clone());
+  }
 
   http::spawn(
-    TcpListener::bind(local_addr(port)).await?,
+    TcpListener::bind(local_addr(options.port)).await?,
     target_tx.clone(),
     config_tx.clone(),
     cp.clone(),
+    Duration::from_millis(options.listener_batch_ms),
+    http::naming::router(np.clone(), service_tx.clone(), service_changed_tx.clone()),
+  );
+  grpc::spawn(
+    local_addr(options.port + 1000).into(),
+    target_tx,
+    config_tx,
+    cp,
+    np,
+    service_tx,
+    service_changed_tx,
   );
-  grpc::spawn(local_addr(port + 1000).into(), target_tx, config_tx, cp);
 
-  Ok(refresh_tx)
+  Ok((refresh_tx, persist_tx))
+}
+
+/// Build the config providers listed in `AWS_LAMBDA_NACOS_ADAPTER_PROVIDERS` in order.
+/// By default, use passthrough mode if the origin is specified,
+/// otherwise use s3 if the bucket is specified, otherwise use fs mode.
+fn config_providers(
+  cache_size: u64,
+  origin: Option<&Origin>,
+  stale_if_error: bool,
+  options: &mut MockNacosOptions,
+) -> Result<Vec<AnyConfigProvider>, Error> {
+  let bucket = env::var("AWS_LAMBDA_NACOS_ADAPTER_S3_BUCKET").ok();
+  let names = env::var("AWS_LAMBDA_NACOS_ADAPTER_PROVIDERS").unwrap_or_else(|_| {
+    match (origin, &bucket) {
+      (Some(_), _) => "passthrough",
+      (None, Some(_)) => "s3",
+      (None, None) => "fs",
+    }
+    .to_string()
+  });
+  debug!("AWS_LAMBDA_NACOS_ADAPTER_PROVIDERS={}", names);
+
+  // providers of aws services share the credentials of the execution role
+  let credentials = aws::Credentials::from_env().map(Arc::new);
+  let signer = |service| match &credentials {
+    Some(credentials) => Ok(Signer::new(credentials.clone(), aws::region(), service)),
+    None => Err(format!(
+      "the {} provider requires AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY",
+      service
+    )),
+  };
+
+  let mut providers = vec![];
+  for name in names.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+    providers.push(match name {
+      "passthrough" => {
+        let Some(origin) = origin else {
+          return Err(
+            "the passthrough provider requires AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ADDRESS".into(),
+          );
+        };
+        let fallback = env::var("AWS_LAMBDA_NACOS_ADAPTER_FALLBACK_PATH")
+          .ok()
+          .map(|path| {
+            debug!("AWS_LAMBDA_NACOS_ADAPTER_FALLBACK_PATH={}", path);
+            FsConfigProvider::new(cache_size, path)
+          });
+        AnyConfigProvider::Passthrough(PassthroughConfigProvider::new(
+          cache_size,
+          origin.clone(),
+          stale_if_error,
+          fallback,
+        ))
+      }
+      "fs" => {
+        let prefix = env::var("AWS_LAMBDA_NACOS_ADAPTER_CONFIG_PATH")
+          .unwrap_or_else(|_| "/mnt/efs/nacos/".to_string());
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_CONFIG_PATH={}", prefix);
+        let versioned = parse_env("AWS_LAMBDA_NACOS_ADAPTER_VERSIONED", false);
+        // releases are immutable in the versioned layout, only the symlink is switched
+        if parse_env("AWS_LAMBDA_NACOS_ADAPTER_WATCH", true) && !versioned {
+          options.watch_path = Some(prefix.clone());
+        }
+        AnyConfigProvider::Fs(
+          FsConfigProvider::new(cache_size, prefix)
+            .with_change_detection(parse_env(
+              "AWS_LAMBDA_NACOS_ADAPTER_CHANGE_DETECTION",
+              ChangeDetection::Metadata,
+            ))
+            .with_versioned(versioned),
+        )
+      }
+      "s3" => {
+        let Some(bucket) = &bucket else {
+          return Err("the s3 provider requires AWS_LAMBDA_NACOS_ADAPTER_S3_BUCKET".into());
+        };
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_S3_BUCKET={}", bucket);
+        let prefix = env::var("AWS_LAMBDA_NACOS_ADAPTER_S3_PREFIX").unwrap_or_default();
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_S3_PREFIX={}", prefix);
+        let endpoint = env::var("AWS_LAMBDA_NACOS_ADAPTER_S3_ENDPOINT").ok();
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_S3_ENDPOINT={:?}", endpoint);
+        AnyConfigProvider::S3(S3ConfigProvider::new(
+          cache_size,
+          bucket,
+          prefix,
+          endpoint.as_deref(),
+          signer("s3")?,
+        ))
+      }
+      "ssm" => {
+        let prefix =
+          env::var("AWS_LAMBDA_NACOS_ADAPTER_SSM_PREFIX").unwrap_or_else(|_| "/nacos".to_string());
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_SSM_PREFIX={}", prefix);
+        let endpoint = env::var("AWS_LAMBDA_NACOS_ADAPTER_SSM_ENDPOINT").ok();
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_SSM_ENDPOINT={:?}", endpoint);
+        AnyConfigProvider::Ssm(
+          SsmConfigProvider::new(
+            cache_size,
+            JsonClient::new(signer("ssm")?, endpoint.as_deref(), "AmazonSSM"),
+            &prefix,
+          )
+          .with_concurrency(options.target_manager_options.concurrency),
+        )
+      }
+      "secrets" => {
+        let prefix = env::var("AWS_LAMBDA_NACOS_ADAPTER_SECRETS_PREFIX")
+          .unwrap_or_else(|_| "nacos/".to_string());
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_SECRETS_PREFIX={}", prefix);
+        let version_stage = env::var("AWS_LAMBDA_NACOS_ADAPTER_SECRETS_VERSION_STAGE")
+          .unwrap_or_else(|_| "AWSCURRENT".to_string());
+        debug!(
+          "AWS_LAMBDA_NACOS_ADAPTER_SECRETS_VERSION_STAGE={}",
+          version_stage
+        );
+        let endpoint = env::var("AWS_LAMBDA_NACOS_ADAPTER_SECRETS_ENDPOINT").ok();
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_SECRETS_ENDPOINT={:?}", endpoint);
+        AnyConfigProvider::Secrets(SecretsConfigProvider::new(
+          cache_size,
+          JsonClient::new(
+            signer("secretsmanager")?,
+            endpoint.as_deref(),
+            "secretsmanager",
+          ),
+          prefix,
+          version_stage,
+        ))
+      }
+      "git" => {
+        let Ok(repo) = env::var("AWS_LAMBDA_NACOS_ADAPTER_GIT_REPO") else {
+          return Err("the git provider requires AWS_LAMBDA_NACOS_ADAPTER_GIT_REPO".into());
+        };
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_GIT_REPO={}", repo);
+        let reference =
+          env::var("AWS_LAMBDA_NACOS_ADAPTER_GIT_REF").unwrap_or_else(|_| "main".to_string());
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_GIT_REF={}", reference);
+        let remote =
+          env::var("AWS_LAMBDA_NACOS_ADAPTER_GIT_REMOTE").unwrap_or_else(|_| "origin".to_string());
+        debug!("AWS_LAMBDA_NACOS_ADAPTER_GIT_REMOTE={}", remote);
+        // an empty remote disables fetching
+        let remote = (!remote.is_empty()).then_some(remote);
+        AnyConfigProvider::Git(
+          GitConfigProvider::new(cache_size, repo, reference, remote)
+            .with_fetch_timeout(parse_env_ms(
+              "AWS_LAMBDA_NACOS_ADAPTER_GIT_FETCH_TIMEOUT_MS",
+              5000,
+            ))
+            .with_fetch_interval(
+              parse_env_ms("AWS_LAMBDA_NACOS_ADAPTER_GIT_FETCH_INTERVAL_MS", 0).unwrap_or_default(),
+            ),
+        )
+      }
+      _ => return Err(format!("unknown config provider: {}", name).into()),
+    });
+  }
+  if providers.is_empty() {
+    return Err("no config provider in AWS_LAMBDA_NACOS_ADAPTER_PROVIDERS".into());
+  }
+  Ok(providers)
 }
 
 fn local_addr(port: u16) -> SocketAddrV4 {
@@ -186,18 +549,84 @@ fn parse_env<T: FromStr + Display + Copy>(name: &str, default: T) -> T {
   v
 }
 
+/// Parse a duration in milliseconds from env, `0` means `None`.
+fn parse_env_ms(name: &str, default: u64) -> Option<Duration> {
+  let ms = parse_env(name, default);
+  (ms != 0).then(|| Duration::from_millis(ms))
+}
+
+/// Read the targets to prefetch from env and the file, invalid targets are skipped.
+fn prefetch_targets() -> Vec<Target> {
+  let mut list = env::var("AWS_LAMBDA_NACOS_ADAPTER_PREFETCH").unwrap_or_default();
+  if let Ok(path) = env::var("AWS_LAMBDA_NACOS_ADAPTER_PREFETCH_FILE") {
+    debug!("AWS_LAMBDA_NACOS_ADAPTER_PREFETCH_FILE={}", path);
+    match std::fs::read_to_string(&path) {
+      Ok(content) => {
+        // one target per line, lines starting with `#` are comments
+        for line in content.lines().filter(|l| !l.trim_start().starts_with('#')) {
+          list.push(',');
+          list.push_str(line);
+        }
+      }
+      Err(e) => warn!(path, error = %e, "failed to read prefetch file"),
+    }
+  }
+
+  list
+    .split(',')
+    .filter(|s| !s.trim().is_empty())
+    .filter_map(|s| s.parse().inspect_err(|e| warn!("{}, skipped", e)).ok())
+    .collect()
+}
+
+/// Read the credentials of the origin nacos server from env.
+/// A static access token takes precedence over username and password.
+fn origin_credentials() -> Credentials {
+  if let Ok(token) = env::var("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_ACCESS_TOKEN") {
+    return Credentials::AccessToken(token);
+  }
+  match (
+    env::var("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_USERNAME"),
+    env::var("AWS_LAMBDA_NACOS_ADAPTER_ORIGIN_PASSWORD"),
+  ) {
+    (Ok(username), Ok(password)) => Credentials::Password { username, password },
+    _ => Credentials::Anonymous,
+  }
+}
+
 /// Return `Ok(true)` if config changed.
-async fn refresh(refresh_tx: &mpsc::Sender<mpsc::Sender<()>>) -> Result<bool> {
-  let (changed_tx, mut changed_rx) = mpsc::channel::<()>(1);
+async fn refresh(refresh_tx: &mpsc::Sender<mpsc::Sender<RefreshEvent>>) -> Result<bool> {
+  let (changed_tx, mut changed_rx) = mpsc::channel::<RefreshEvent>(1);
   refresh_tx.send(changed_tx).await?;
 
-  let mut changed = false;
+  let (mut changed, mut skipped) = (0, 0);
+  let (mut failed, mut timed_out) = (vec![], vec![]);
   let now = Instant::now();
-  while let Some(()) = changed_rx.recv().await {
-    changed = true;
+  while let Some(event) = changed_rx.recv().await {
+    match event {
+      RefreshEvent::Changed(target) => {
+        debug!(?target, "config changed");
+        changed += 1;
+      }
+      RefreshEvent::Failed(target) => failed.push(target),
+      RefreshEvent::TimedOut(target) => timed_out.push(target),
+      RefreshEvent::DeadlineExceeded(n) => skipped += n,
+      RefreshEvent::ServiceChanged(key) => {
+        debug!(?key, "service changed");
+        changed += 1;
+      }
+    }
   }
   // now changed_rx.recv() returns None, meaning all changed_tx are dropped and the refresh is done
   debug!(changed, "refresh done: {}ms", now.elapsed().as_millis());
+  if !failed.is_empty() || !timed_out.is_empty() || skipped > 0 {
+    warn!(
+      ?failed,
+      ?timed_out,
+      skipped,
+      "some targets are not refreshed"
+    );
+  }
 
-  Ok(changed)
+  Ok(changed > 0)
 }
@@ -0,0 +1,161 @@
+//! Service discovery, served from a static registry or the origin nacos server,
+//! plus instances registered by local clients.
+
+pub mod fs;
+pub mod local;
+pub mod passthrough;
+pub mod provider;
+pub mod subscription;
+
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::HashMap,
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The namespace used by nacos when the namespace is not specified.
+pub const DEFAULT_NAMESPACE: &str = "public";
+
+/// Identifies a service. This is cheap to clone.
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct ServiceKey {
+  /// `None` for the public namespace.
+  pub namespace: Option<Arc<String>>,
+  pub group: Arc<String>,
+  pub name: Arc<String>,
+}
+
+impl ServiceKey {
+  /// Nacos clients send the service name as `{group}@@{name}` in some APIs,
+  /// the group in the service name takes precedence over `group`.
+  pub fn new(namespace: Option<&str>, group: &str, name: &str) -> Self {
+    let (group, name) = name.split_once("@@").unwrap_or((group, name));
+    ServiceKey {
+      namespace: namespace
+        .filter(|ns| !ns.is_empty() && *ns != DEFAULT_NAMESPACE)
+        .map(|ns| Arc::new(ns.to_string())),
+      group: Arc::new(group.to_string()),
+      name: Arc::new(name.to_string()),
+    }
+  }
+
+  pub fn namespace(&self) -> &str {
+    self
+      .namespace
+      .as_deref()
+      .map_or(DEFAULT_NAMESPACE, |s| s.as_str())
+  }
+
+  /// Return `"{group}@@{name}"`.
+  pub fn grouped_name(&self) -> String {
+    format!("{}@@{}", self.group, self.name)
+  }
+}
+
+/// An instance of a service, in the format of the static registry.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Instance {
+  pub ip: String,
+  pub port: u32,
+  #[serde(default = "default_weight")]
+  pub weight: f64,
+  #[serde(default = "default_true")]
+  pub healthy: bool,
+  #[serde(default = "default_true")]
+  pub enabled: bool,
+  #[serde(default = "default_true")]
+  pub ephemeral: bool,
+  #[serde(default = "default_cluster")]
+  pub cluster_name: String,
+  #[serde(default)]
+  pub metadata: HashMap<String, String>,
+}
+
+fn default_weight() -> f64 {
+  1.0
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_cluster() -> String {
+  "DEFAULT".to_string()
+}
+
+impl Instance {
+  /// Like nacos, the instance id is `{ip}#{port}#{cluster}#{group}@@{service}`.
+  pub fn id(&self, key: &ServiceKey) -> String {
+    format!(
+      "{}#{}#{}#{}",
+      self.ip,
+      self.port,
+      self.cluster_name,
+      key.grouped_name()
+    )
+  }
+
+  /// Two instances are the same if they have the same address in the same cluster.
+  pub fn same_as(&self, other: &Instance) -> bool {
+    self.ip == other.ip && self.port == other.port && self.cluster_name == other.cluster_name
+  }
+}
+
+/// Who registered an instance locally.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Owner {
+  /// A gRPC connection with the id, its instances are deregistered when it's closed.
+  Connection(String),
+  /// A client of the http api, its ephemeral instances expire if it stops sending beats.
+  Http,
+}
+
+#[derive(Clone, Debug)]
+pub struct Service {
+  instances: Vec<Instance>,
+  checksum: String,
+}
+
+impl Service {
+  pub fn new(mut instances: Vec<Instance>) -> Self {
+    // sort instances so the checksum doesn't depend on the order
+    instances
+      .sort_by(|a, b| (&a.ip, a.port, &a.cluster_name).cmp(&(&b.ip, b.port, &b.cluster_name)));
+    let json = serde_json::to_string(&instances).expect("failed to serialize instances");
+    Service {
+      checksum: format!("{:x}", md5::compute(json)),
+      instances,
+    }
+  }
+
+  pub fn instances(&self) -> &[Instance] {
+    &self.instances
+  }
+
+  /// Changes whenever any instance changes.
+  pub fn checksum(&self) -> &str {
+    &self.checksum
+  }
+
+  /// Return instances in `clusters` (all clusters if empty), only healthy ones if `healthy_only`.
+  pub fn select<'a>(
+    &'a self,
+    clusters: &'a [&str],
+    healthy_only: bool,
+  ) -> impl Iterator<Item = &'a Instance> {
+    self.instances.iter().filter(move |instance| {
+      (clusters.is_empty() || clusters.contains(&instance.cluster_name.as_str()))
+        && (!healthy_only || (instance.healthy && instance.enabled))
+    })
+  }
+}
+
+/// Return the current time in milliseconds, used as `lastRefTime` of services.
+pub fn now_millis() -> i64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default()
+    .as_millis() as i64
+}
@@ -0,0 +1,122 @@
+use super::{provider::NamingProvider, Instance, Service, ServiceKey, DEFAULT_NAMESPACE};
+use crate::{
+  config::fs::{ChangeDetection, FileVersion},
+  error::ProviderError,
+};
+use moka::future::Cache;
+use std::{io, sync::Arc};
+use tokio::fs;
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+struct CacheValue {
+  version: FileVersion,
+  service: Arc<Service>,
+}
+
+/// Serve services from a static registry,
+/// where each service is a JSON array of instances at `{prefix}{namespace}/{group}/{service}`.
+#[derive(Clone, Debug)]
+pub struct FsNamingProvider {
+  /// Moka cache, which is cheap to clone.
+  cache: Cache<String, CacheValue>,
+  /// Wrapped in an Arc to make [`Self`] cheap to clone.
+  prefix: Arc<String>,
+  detection: ChangeDetection,
+}
+
+impl FsNamingProvider {
+  pub fn new(size: u64, prefix: String) -> Self {
+    FsNamingProvider {
+      cache: Cache::new(size),
+      prefix: Arc::new(prefix),
+      detection: ChangeDetection::Metadata,
+    }
+  }
+
+  /// Services are only compared by their instances,
+  /// so [`ChangeDetection::Content`] works like [`ChangeDetection::Metadata`].
+  pub fn with_change_detection(mut self, detection: ChangeDetection) -> Self {
+    self.detection = detection;
+    self
+  }
+}
+
+impl NamingProvider for FsNamingProvider {
+  async fn get(&mut self, key: &ServiceKey, refresh: bool) -> Result<Arc<Service>, ProviderError> {
+    let path = format!(
+      "{}{}/{}/{}",
+      self.prefix,
+      key.namespace(),
+      key.group,
+      key.name
+    );
+
+    let version = if !refresh {
+      // if not refresh and value in cache, return it
+      if let Some(value) = self.cache.get(&path).await {
+        return Ok(value.service);
+      }
+      FileVersion::of(&fs::metadata(&path).await?, self.detection)
+    } else {
+      // check cache by version, like configs
+      let version = match fs::metadata(&path).await {
+        Ok(metadata) => FileVersion::of(&metadata, self.detection),
+        Err(e) => {
+          // the file might be deleted, don't serve the cached service anymore
+          self.cache.invalidate(&path).await;
+          return Err(e.into());
+        }
+      };
+      if let Some(value) = self.cache.get(&path).await {
+        if value.version == version {
+          return Ok(value.service);
+        }
+      }
+      version
+    };
+
+    let content = fs::read(&path).await?;
+    let instances: Vec<Instance> = serde_json::from_slice(&content)
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let service = Arc::new(Service::new(instances));
+    self
+      .cache
+      .insert(
+        path,
+        CacheValue {
+          version,
+          service: service.clone(),
+        },
+      )
+      .await;
+    Ok(service)
+  }
+
+  async fn list(
+    &mut self,
+    namespace: Option<&str>,
+    group: &str,
+  ) -> Result<Vec<String>, ProviderError> {
+    let dir = format!(
+      "{}{}/{}",
+      self.prefix,
+      namespace.unwrap_or(DEFAULT_NAMESPACE),
+      group
+    );
+    let mut names = vec![];
+    let mut entries = match fs::read_dir(&dir).await {
+      Ok(entries) => entries,
+      // an empty group
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+      Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      if entry.file_type().await?.is_file() {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+      }
+    }
+    names.sort();
+    Ok(names)
+  }
+}
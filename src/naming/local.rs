@@ -0,0 +1,148 @@
+use super::{provider::NamingProvider, Instance, Owner, Service, ServiceKey, DEFAULT_NAMESPACE};
+use crate::error::ProviderError;
+use std::{
+  collections::HashMap,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// Ephemeral instances registered over http are removed if no beat is received for this,
+/// like `ipDeleteTimeout` in nacos.
+const HTTP_INSTANCE_TTL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+struct Registration {
+  owner: Owner,
+  instance: Instance,
+  last_beat: Instant,
+}
+
+impl Registration {
+  fn is_expired(&self, now: Instant) -> bool {
+    self.owner == Owner::Http
+      && self.instance.ephemeral
+      && now.duration_since(self.last_beat) > HTTP_INSTANCE_TTL
+  }
+}
+
+/// Wrap a naming provider and add instances registered by local clients,
+/// which are only visible in this sandbox.
+#[derive(Clone, Debug)]
+pub struct LocalNamingProvider<NP> {
+  inner: NP,
+  /// Registered instances of each service, an instance might be registered by several owners.
+  registry: Arc<Mutex<HashMap<ServiceKey, Vec<Registration>>>>,
+}
+
+impl<NP> LocalNamingProvider<NP> {
+  pub fn new(inner: NP) -> Self {
+    LocalNamingProvider {
+      inner,
+      registry: Default::default(),
+    }
+  }
+
+  /// Return instances registered for the service, expired ones are removed.
+  fn registered(&self, key: &ServiceKey) -> Vec<Instance> {
+    let mut registry = self.registry.lock().unwrap();
+    let Some(registrations) = registry.get_mut(key) else {
+      return vec![];
+    };
+    let now = Instant::now();
+    registrations.retain(|r| !r.is_expired(now));
+    // the latest registration of the same instance takes precedence
+    let mut instances: Vec<Instance> = vec![];
+    for registration in registrations.iter().rev() {
+      if !instances.iter().any(|i| i.same_as(&registration.instance)) {
+        instances.push(registration.instance.clone());
+      }
+    }
+    if registrations.is_empty() {
+      registry.remove(key);
+    }
+    instances
+  }
+}
+
+impl<NP: NamingProvider> NamingProvider for LocalNamingProvider<NP> {
+  /// A service which doesn't exist in the inner provider is served as an empty service.
+  async fn get(&mut self, key: &ServiceKey, refresh: bool) -> Result<Arc<Service>, ProviderError> {
+    let service = match self.inner.get(key, refresh).await {
+      Ok(service) => service,
+      Err(ProviderError::NotFound) => Arc::new(Service::new(vec![])),
+      Err(e) => return Err(e),
+    };
+
+    let registered = self.registered(key);
+    if registered.is_empty() {
+      return Ok(service);
+    }
+    // registered instances take precedence over the same instances from the inner provider
+    let mut instances: Vec<_> = service
+      .instances()
+      .iter()
+      .filter(|instance| !registered.iter().any(|r| r.same_as(instance)))
+      .cloned()
+      .collect();
+    instances.extend(registered);
+    Ok(Arc::new(Service::new(instances)))
+  }
+
+  async fn list(
+    &mut self,
+    namespace: Option<&str>,
+    group: &str,
+  ) -> Result<Vec<String>, ProviderError> {
+    let mut names = self.inner.list(namespace, group).await?;
+    let keys: Vec<_> = self.registry.lock().unwrap().keys().cloned().collect();
+    for key in keys {
+      if key.namespace() == namespace.unwrap_or(DEFAULT_NAMESPACE)
+        && key.group.as_str() == group
+        && !names.contains(&key.name)
+        && !self.registered(&key).is_empty()
+      {
+        names.push(key.name.to_string());
+      }
+    }
+    Ok(names)
+  }
+
+  fn register(&self, owner: Owner, key: &ServiceKey, instance: Instance) {
+    let mut registry = self.registry.lock().unwrap();
+    let registrations = registry.entry(key.clone()).or_default();
+    registrations.retain(|r| !(r.owner == owner && r.instance.same_as(&instance)));
+    registrations.push(Registration {
+      owner,
+      instance,
+      last_beat: Instant::now(),
+    });
+  }
+
+  fn deregister(&self, owner: Owner, key: &ServiceKey, instance: &Instance) {
+    let mut registry = self.registry.lock().unwrap();
+    if let Some(registrations) = registry.get_mut(key) {
+      registrations.retain(|r| !(r.owner == owner && r.instance.same_as(instance)));
+      if registrations.is_empty() {
+        registry.remove(key);
+      }
+    }
+  }
+
+  fn beat(&self, owner: Owner, key: &ServiceKey, instance: &Instance) -> bool {
+    let now = Instant::now();
+    let mut registry = self.registry.lock().unwrap();
+    let registration = registry.get_mut(key).and_then(|registrations| {
+      registrations
+        .iter_mut()
+        .find(|r| r.owner == owner && r.instance.same_as(instance) && !r.is_expired(now))
+    });
+    match registration {
+      Some(registration) => {
+        registration.last_beat = now;
+        true
+      }
+      None => false,
+    }
+  }
+}
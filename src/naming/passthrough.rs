@@ -0,0 +1,122 @@
+use super::{provider::NamingProvider, Instance, Service, ServiceKey, DEFAULT_NAMESPACE};
+use crate::{error::ProviderError, origin::Origin};
+use lambda_extension::tracing::warn;
+use moka::future::Cache;
+use reqwest::{Response, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct InstanceList {
+  #[serde(default)]
+  hosts: Vec<Instance>,
+}
+
+#[derive(Deserialize)]
+struct ServiceList {
+  #[serde(default)]
+  doms: Vec<String>,
+}
+
+/// Serve services from the origin nacos server.
+#[derive(Clone, Debug)]
+pub struct PassthroughNamingProvider {
+  /// Moka cache, which is cheap to clone.
+  cache: Cache<ServiceKey, Arc<Service>>,
+  /// The origin nacos server, which is cheap to clone.
+  origin: Origin,
+  /// Serve the cached service when the origin is unreachable.
+  stale_if_error: bool,
+}
+
+impl PassthroughNamingProvider {
+  pub fn new(size: u64, origin: Origin, stale_if_error: bool) -> Self {
+    PassthroughNamingProvider {
+      cache: Cache::new(size),
+      origin,
+      stale_if_error,
+    }
+  }
+
+  async fn fetch(&self, key: &ServiceKey) -> Result<Arc<Service>, ProviderError> {
+    let res = self
+      .origin
+      .get(
+        "/nacos/v1/ns/instance/list",
+        &[
+          ("serviceName", &key.name),
+          ("groupName", &key.group),
+          ("namespaceId", key.namespace()),
+        ],
+      )
+      .await?;
+    let list: InstanceList = parse(res).await?;
+    Ok(Arc::new(Service::new(list.hosts)))
+  }
+}
+
+impl NamingProvider for PassthroughNamingProvider {
+  async fn get(&mut self, key: &ServiceKey, refresh: bool) -> Result<Arc<Service>, ProviderError> {
+    let cached = self.cache.get(key).await;
+    if !refresh {
+      if let Some(service) = cached {
+        return Ok(service);
+      }
+    }
+
+    match self.fetch(key).await {
+      Ok(service) => {
+        self.cache.insert(key.clone(), service.clone()).await;
+        Ok(service)
+      }
+      Err(ProviderError::NotFound) => {
+        self.cache.invalidate(key).await;
+        Err(ProviderError::NotFound)
+      }
+      Err(err) => match cached.filter(|_| self.stale_if_error) {
+        Some(service) => {
+          warn!(?key, error = %err, "failed to fetch service from origin, serve cached service");
+          Ok(service)
+        }
+        None => Err(err),
+      },
+    }
+  }
+
+  async fn list(
+    &mut self,
+    namespace: Option<&str>,
+    group: &str,
+  ) -> Result<Vec<String>, ProviderError> {
+    let res = self
+      .origin
+      .get(
+        "/nacos/v1/ns/service/list",
+        &[
+          ("pageNo", "1"),
+          ("pageSize", "1000"),
+          ("groupName", group),
+          ("namespaceId", namespace.unwrap_or(DEFAULT_NAMESPACE)),
+        ],
+      )
+      .await?;
+    let list: ServiceList = parse(res).await?;
+    Ok(list.doms)
+  }
+}
+
+/// Map the response of the origin like [`crate::config::passthrough::PassthroughConfigProvider`] does.
+async fn parse<T: DeserializeOwned>(res: Response) -> Result<T, ProviderError> {
+  match res.status() {
+    StatusCode::OK => serde_json::from_str(&res.text().await?)
+      .map_err(|e| ProviderError::Upstream(format!("invalid response from origin: {}", e))),
+    StatusCode::NOT_FOUND => Err(ProviderError::NotFound),
+    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+      Err(ProviderError::Unauthorized(res.text().await?))
+    }
+    status => Err(ProviderError::Upstream(format!(
+      "origin responded with {}",
+      status
+    ))),
+  }
+}
@@ -0,0 +1,35 @@
+use super::{Instance, Owner, Service, ServiceKey};
+use crate::error::ProviderError;
+use std::{future::Future, sync::Arc};
+
+/// This should be cheap to clone.
+pub trait NamingProvider: Clone + Send + Sync {
+  /// Return [`ProviderError::NotFound`] if the service doesn't exist.
+  fn get(
+    &mut self,
+    key: &ServiceKey,
+    refresh: bool,
+  ) -> impl Future<Output = Result<Arc<Service>, ProviderError>> + Send;
+
+  /// List service names in the group.
+  fn list(
+    &mut self,
+    namespace: Option<&str>,
+    group: &str,
+  ) -> impl Future<Output = Result<Vec<String>, ProviderError>> + Send;
+
+  /// Register an instance of the service on behalf of the owner.
+  /// The default implementation ignores it.
+  fn register(&self, _owner: Owner, _key: &ServiceKey, _instance: Instance) {}
+
+  /// Deregister an instance of the service registered by the owner.
+  /// The default implementation ignores it.
+  fn deregister(&self, _owner: Owner, _key: &ServiceKey, _instance: &Instance) {}
+
+  /// Keep an instance registered by the owner alive,
+  /// return `false` if it's not registered, then the client should register it again.
+  /// The default implementation ignores it and returns `true`.
+  fn beat(&self, _owner: Owner, _key: &ServiceKey, _instance: &Instance) -> bool {
+    true
+  }
+}
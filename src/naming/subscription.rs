@@ -0,0 +1,162 @@
+use super::{provider::NamingProvider, ServiceKey};
+use crate::config::target::{RefreshEvent, TargetManagerOptions};
+use futures::{stream, StreamExt};
+use lambda_extension::tracing::{debug, warn};
+use std::collections::HashMap;
+use tokio::{
+  sync::{broadcast, mpsc},
+  time::Instant,
+};
+
+/// Messages sent to the subscription manager.
+#[derive(Debug)]
+pub enum ServiceMessage {
+  /// A client queried the service and got the checksum, the service will be refreshed until it expires.
+  /// Also sent when a subscriber is notified of the change.
+  Register(ServiceKey, String),
+  /// A gRPC connection subscribes the service and got the checksum.
+  /// Subscribed services never expire.
+  Subscribe(ServiceKey, String),
+  /// The connection unsubscribes the service.
+  Unsubscribe(ServiceKey),
+}
+
+struct ServiceState {
+  /// The checksum of the service which clients got.
+  checksum: String,
+  /// How many connections are subscribing this service.
+  subscribers: usize,
+  last_seen: Instant,
+  /// Kept until a subscriber gets the latest service, so the refresh waits for it.
+  changed_tx: Option<mpsc::Sender<RefreshEvent>>,
+}
+
+/// Refresh queried services along with configs, and notify subscribers of changed services.
+/// The options are shared with the target manager.
+pub fn spawn_subscription_manager(
+  np: impl NamingProvider + 'static,
+  mut refresh_rx: mpsc::Receiver<mpsc::Sender<RefreshEvent>>,
+  options: TargetManagerOptions,
+) -> (mpsc::Sender<ServiceMessage>, broadcast::Sender<ServiceKey>) {
+  let (service_tx, mut service_rx) = mpsc::channel::<ServiceMessage>(1);
+  // changed services are broadcast to the bi-streams
+  let (changed_tx, _) = broadcast::channel(64);
+
+  tokio::spawn({
+    let changed_tx = changed_tx.clone();
+    async move {
+      let mut services = HashMap::new();
+      loop {
+        tokio::select! {
+          message = service_rx.recv() => {
+            debug!("service message: {:?}", message);
+            let Some(message) = message else { break };
+            match message {
+              ServiceMessage::Register(key, checksum) => {
+                touch(&mut services, key, checksum);
+              }
+              ServiceMessage::Subscribe(key, checksum) => {
+                touch(&mut services, key, checksum).subscribers += 1;
+              }
+              ServiceMessage::Unsubscribe(key) => {
+                if let Some(state) = services.get_mut(&key) {
+                  state.subscribers = state.subscribers.saturating_sub(1);
+                  state.last_seen = Instant::now();
+                  if state.subscribers == 0 {
+                    // no one is left to get the change
+                    state.changed_tx = None;
+                  }
+                }
+              }
+            }
+          }
+          done_tx = refresh_rx.recv() => {
+            // the refresh is done when `done_tx` is dropped
+            let Some(done_tx) = done_tx else { break };
+
+            if let Some(ttl) = options.ttl {
+              services.retain(|key, state| {
+                let expired = state.subscribers == 0 && state.last_seen.elapsed() > ttl;
+                if expired {
+                  debug!("service expired: {:?}", key);
+                }
+                !expired
+              });
+            }
+
+            let refresh = stream::iter(services.iter_mut()).for_each_concurrent(options.concurrency, |(key, state)| {
+              let mut np = np.clone();
+              let changed_tx = changed_tx.clone();
+              let done_tx = done_tx.clone();
+              async move {
+                let get = np.get(key, true);
+                let result = match options.timeout {
+                  Some(timeout) => tokio::time::timeout(timeout, get).await.ok(),
+                  None => Some(get.await),
+                };
+                let service = match result {
+                  Some(Ok(service)) => service,
+                  Some(Err(e)) => {
+                    warn!(?key, error = %e, "failed to refresh service");
+                    return;
+                  }
+                  None => {
+                    warn!(?key, "refreshing service timed out");
+                    return;
+                  }
+                };
+                if service.checksum() != state.checksum {
+                  debug!(?key, "service changed");
+                  state.checksum = service.checksum().to_owned();
+                  done_tx
+                    .send(RefreshEvent::ServiceChanged(key.clone()))
+                    .await
+                    .expect("done_tx.send failed");
+                  if state.subscribers > 0 {
+                    state.changed_tx = Some(done_tx);
+                  }
+                  // it's ok if no bi-stream is connected
+                  changed_tx.send(key.clone()).ok();
+                }
+              }
+            });
+
+            match options.deadline {
+              Some(deadline) => {
+                if tokio::time::timeout(deadline, refresh).await.is_err() {
+                  warn!("refreshing services exceeded the deadline");
+                }
+              }
+              None => refresh.await,
+            }
+            drop(done_tx);
+          }
+        }
+      }
+      debug!("subscription manager is stopped");
+    }
+  });
+
+  (service_tx, changed_tx)
+}
+
+/// Update the checksum and the last seen time of the service, insert it if not exists.
+/// The refresh waiting for the service is released if the checksum is the latest.
+fn touch(
+  services: &mut HashMap<ServiceKey, ServiceState>,
+  key: ServiceKey,
+  checksum: String,
+) -> &mut ServiceState {
+  let state = services.entry(key).or_insert_with(|| ServiceState {
+    checksum: String::new(),
+    subscribers: 0,
+    last_seen: Instant::now(),
+    changed_tx: None,
+  });
+  if state.checksum == checksum {
+    state.changed_tx = None;
+  }
+  state.checksum = checksum;
+  state.last_seen = Instant::now();
+  state
+}
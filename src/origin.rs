@@ -0,0 +1,152 @@
+//! Client of the origin Nacos server, used in passthrough mode.
+
+pub mod auth;
+pub mod server_list;
+
+use crate::error::ProviderError;
+use auth::Auth;
+use lambda_extension::tracing::{debug, warn};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use server_list::ServerList;
+
+/// A failed request to a node.
+struct SendError {
+  error: ProviderError,
+  /// Whether the request might have reached the node.
+  sent: bool,
+}
+
+impl SendError {
+  fn unsent(error: ProviderError) -> Self {
+    SendError { error, sent: false }
+  }
+}
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Origin {
+  /// Reqwest client, which is cheap to clone.
+  client: Client,
+  servers: ServerList,
+  auth: Auth,
+}
+
+impl Origin {
+  pub fn new(servers: ServerList, auth: Auth) -> Self {
+    Origin {
+      client: Client::new(),
+      servers,
+      auth,
+    }
+  }
+
+  /// Send a GET request to `path` with `params`.
+  pub async fn get(&self, path: &str, params: &[(&str, &str)]) -> Result<Response, ProviderError> {
+    self.send(Method::GET, path, params).await
+  }
+
+  /// Send a POST request to `path` with `params` as the form body.
+  pub async fn post(&self, path: &str, params: &[(&str, &str)]) -> Result<Response, ProviderError> {
+    self.send(Method::POST, path, params).await
+  }
+
+  /// Send a DELETE request to `path` with `params`.
+  pub async fn delete(
+    &self,
+    path: &str,
+    params: &[(&str, &str)],
+  ) -> Result<Response, ProviderError> {
+    self.send(Method::DELETE, path, params).await
+  }
+
+  /// If the node fails (a transport error or a 5xx response), retry on the next node.
+  /// Writes are only retried if they were not sent, since they are not idempotent,
+  /// e.g. a publish with `casMd5` applied by a node which timed out would conflict on the next node.
+  async fn send(
+    &self,
+    method: Method,
+    path: &str,
+    params: &[(&str, &str)],
+  ) -> Result<Response, ProviderError> {
+    let mut last = Err(ProviderError::Upstream(
+      "no origin node is available".to_string(),
+    ));
+
+    for index in self.servers.candidates() {
+      let addr = self.servers.addr(index);
+      match self.send_to(&method, addr, path, params).await {
+        Ok(res) if !res.status().is_server_error() => {
+          self.servers.succeed(index);
+          return Ok(res);
+        }
+        Ok(res) => {
+          warn!(addr, path, status = %res.status(), "origin node responded with server error");
+          self.servers.fail(index);
+          if method != Method::GET {
+            return Ok(res);
+          }
+          last = Ok(res);
+        }
+        Err(e) => {
+          warn!(addr, path, error = %e.error, "failed to request origin node");
+          self.servers.fail(index);
+          if method != Method::GET && e.sent {
+            return Err(e.error);
+          }
+          last = Err(e.error);
+        }
+      }
+    }
+
+    last
+  }
+
+  /// Send a request to the node at `addr`.
+  /// The access token will be attached if the authentication is enabled.
+  /// If the node responds with 403, login again and retry once.
+  async fn send_to(
+    &self,
+    method: &Method,
+    addr: &str,
+    path: &str,
+    params: &[(&str, &str)],
+  ) -> Result<Response, SendError> {
+    let mut retried = false;
+    loop {
+      // params of POST requests are sent in the body, since the content of a config might be large
+      let query = if *method == Method::POST { &[] } else { params };
+      let mut url =
+        Url::parse_with_params(&format!("http://{}{}", addr, path), query).map_err(|e| {
+          SendError::unsent(ProviderError::Upstream(format!(
+            "invalid origin address {}: {}",
+            addr, e
+          )))
+        })?;
+      if let Some(token) = self
+        .auth
+        .token(&self.client, addr)
+        .await
+        .map_err(SendError::unsent)?
+      {
+        url.query_pairs_mut().append_pair("accessToken", &token);
+      }
+
+      let mut req = self.client.request(method.clone(), url);
+      if *method == Method::POST {
+        req = req.form(params);
+      }
+      let res = req.send().await.map_err(|e| SendError {
+        // the request might have reached the node unless it failed to connect
+        sent: !e.is_connect(),
+        error: e.into(),
+      })?;
+      if res.status() == StatusCode::FORBIDDEN && !retried && self.auth.can_login() {
+        debug!(addr, path, "origin responded 403, login again");
+        self.auth.invalidate().await;
+        retried = true;
+        continue;
+      }
+      return Ok(res);
+    }
+  }
+}
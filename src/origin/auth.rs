@@ -0,0 +1,128 @@
+use crate::error::ProviderError;
+use lambda_extension::tracing::debug;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::Mutex, time::Instant};
+
+pub enum Credentials {
+  /// Access the origin anonymously.
+  Anonymous,
+  /// A static access token which will be attached to every request as is.
+  AccessToken(String),
+  /// Login with username and password to get the access token.
+  Password { username: String, password: String },
+}
+
+// don't leak credentials in logs
+impl std::fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Credentials::Anonymous => write!(f, "Anonymous"),
+      Credentials::AccessToken(_) => write!(f, "AccessToken"),
+      Credentials::Password { username, .. } => write!(f, "Password({})", username),
+    }
+  }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoginResponse {
+  access_token: String,
+  /// In seconds.
+  token_ttl: u64,
+}
+
+struct Token {
+  value: String,
+  /// The token should be refreshed after this.
+  refresh_at: Instant,
+}
+
+// don't leak the token in logs
+impl std::fmt::Debug for Token {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Token")
+      .field("refresh_at", &self.refresh_at)
+      .finish_non_exhaustive()
+  }
+}
+
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct Auth {
+  /// Wrapped in an Arc to make [`Self`] cheap to clone.
+  credentials: Arc<Credentials>,
+  /// The access token got by login.
+  /// The mutex also prevents concurrent logins.
+  token: Arc<Mutex<Option<Token>>>,
+}
+
+impl Auth {
+  pub fn new(credentials: Credentials) -> Self {
+    Auth {
+      credentials: Arc::new(credentials),
+      token: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  /// Return `true` if a new token can be got by login.
+  pub fn can_login(&self) -> bool {
+    matches!(*self.credentials, Credentials::Password { .. })
+  }
+
+  /// Return the access token, login if the cached token is missing or expired.
+  /// Return `Ok(None)` if the authentication is disabled.
+  pub async fn token(&self, client: &Client, addr: &str) -> Result<Option<String>, ProviderError> {
+    let (username, password) = match &*self.credentials {
+      Credentials::Anonymous => return Ok(None),
+      Credentials::AccessToken(token) => return Ok(Some(token.clone())),
+      Credentials::Password { username, password } => (username, password),
+    };
+
+    let mut token = self.token.lock().await;
+    if let Some(token) = token.as_ref() {
+      if token.refresh_at > Instant::now() {
+        return Ok(Some(token.value.clone()));
+      }
+    }
+
+    debug!(username, "login to origin");
+    let res = client
+      .post(format!("http://{}/nacos/v1/auth/login", addr))
+      .form(&[("username", username), ("password", password)])
+      .send()
+      .await?;
+    match res.status() {
+      StatusCode::OK => {}
+      status if status.is_client_error() => {
+        return Err(ProviderError::Unauthorized(format!(
+          "failed to login to origin: {}",
+          status
+        )))
+      }
+      status => {
+        return Err(ProviderError::Upstream(format!(
+          "failed to login to origin: {}",
+          status
+        )))
+      }
+    }
+    let res: LoginResponse = serde_json::from_str(&res.text().await?)
+      .map_err(|e| ProviderError::Upstream(format!("invalid login response: {}", e)))?;
+
+    // refresh the token before it expires, like the official nacos client does
+    let ttl = Duration::from_secs(res.token_ttl);
+    *token = Some(Token {
+      value: res.access_token.clone(),
+      refresh_at: Instant::now() + ttl - ttl / 10,
+    });
+
+    Ok(Some(res.access_token))
+  }
+
+  /// Drop the cached token, so the next [`Self::token`] will login again.
+  pub async fn invalidate(&self) {
+    self.token.lock().await.take();
+  }
+}
@@ -0,0 +1,226 @@
+use lambda_extension::tracing::{debug, warn};
+use std::{
+  fmt::Display,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+use tokio::time::Instant;
+
+/// How to choose the node for the next request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Selection {
+  /// Rotate through nodes for every request.
+  RoundRobin,
+  /// Keep using the same node until it fails.
+  Sticky,
+}
+
+impl FromStr for Selection {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "round-robin" => Ok(Selection::RoundRobin),
+      "sticky" => Ok(Selection::Sticky),
+      _ => Err(format!("unknown selection: {}", s)),
+    }
+  }
+}
+
+impl Display for Selection {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Selection::RoundRobin => write!(f, "round-robin"),
+      Selection::Sticky => write!(f, "sticky"),
+    }
+  }
+}
+
+#[derive(Debug)]
+struct Node {
+  addr: String,
+  /// Consecutive failures.
+  failures: AtomicU32,
+  /// Until this, the node is only tried after healthy nodes failed.
+  ejected_until: Mutex<Option<Instant>>,
+}
+
+impl Node {
+  fn is_ejected(&self, now: Instant) -> bool {
+    self
+      .ejected_until
+      .lock()
+      .unwrap()
+      .is_some_and(|until| until > now)
+  }
+}
+
+/// Nodes of the origin nacos cluster, like the `serverAddr` of the official nacos client.
+/// This is cheap to clone.
+#[derive(Clone, Debug)]
+pub struct ServerList {
+  nodes: Arc<Vec<Node>>,
+  /// Index of the next node to choose.
+  next: Arc<AtomicUsize>,
+  selection: Selection,
+  /// Eject a node after this number of consecutive failures.
+  max_failures: u32,
+  /// How long an ejected node won't be chosen.
+  eject: Duration,
+}
+
+impl ServerList {
+  /// Create a server list from comma separated addresses like `10.0.0.1:8848,10.0.0.2:8848`.
+  pub fn new(addrs: &str, selection: Selection, max_failures: u32, eject: Duration) -> Self {
+    let nodes = addrs
+      .split(',')
+      .map(str::trim)
+      .filter(|s| !s.is_empty())
+      .map(|addr| Node {
+        addr: addr.to_string(),
+        failures: AtomicU32::new(0),
+        ejected_until: Mutex::new(None),
+      })
+      .collect::<Vec<_>>();
+    if nodes.is_empty() {
+      warn!("no origin address is provided");
+    }
+
+    ServerList {
+      nodes: Arc::new(nodes),
+      next: Arc::new(AtomicUsize::new(0)),
+      selection,
+      max_failures,
+      eject,
+    }
+  }
+
+  /// Return indexes of nodes in the order they should be tried for a request.
+  /// Healthy nodes come first, ejected nodes are kept at the end as the last resort.
+  pub fn candidates(&self) -> Vec<usize> {
+    let len = self.nodes.len();
+    if len == 0 {
+      return vec![];
+    }
+
+    let start = match self.selection {
+      Selection::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+      Selection::Sticky => self.next.load(Ordering::Relaxed),
+    } % len;
+
+    let now = Instant::now();
+    let (healthy, ejected): (Vec<_>, Vec<_>) = (0..len)
+      .map(|i| (start + i) % len)
+      .partition(|&i| !self.nodes[i].is_ejected(now));
+    healthy.into_iter().chain(ejected).collect()
+  }
+
+  pub fn addr(&self, index: usize) -> &str {
+    &self.nodes[index].addr
+  }
+
+  pub fn succeed(&self, index: usize) {
+    let node = &self.nodes[index];
+    node.failures.store(0, Ordering::Relaxed);
+    node.ejected_until.lock().unwrap().take();
+  }
+
+  pub fn fail(&self, index: usize) {
+    let node = &self.nodes[index];
+    let failures = node.failures.fetch_add(1, Ordering::Relaxed) + 1;
+    debug!(addr = node.addr, failures, "origin node failed");
+
+    if failures >= self.max_failures {
+      warn!(
+        addr = node.addr,
+        failures, "eject origin node for {:?}", self.eject
+      );
+      *node.ejected_until.lock().unwrap() = Some(Instant::now() + self.eject);
+    }
+
+    if self.selection == Selection::Sticky {
+      // move to the next node, unless another request already did
+      let len = self.nodes.len();
+      let _ = self.next.compare_exchange(
+        index,
+        (index + 1) % len,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn addrs(servers: &ServerList, candidates: Vec<usize>) -> Vec<&str> {
+    candidates.into_iter().map(|i| servers.addr(i)).collect()
+  }
+
+  #[test]
+  fn new_skips_empty_addresses() {
+    let servers = ServerList::new(" a:1, ,b:2,", Selection::RoundRobin, 3, Duration::ZERO);
+    assert_eq!(addrs(&servers, servers.candidates()), ["a:1", "b:2"]);
+
+    let servers = ServerList::new(" , ", Selection::RoundRobin, 3, Duration::ZERO);
+    assert!(servers.candidates().is_empty());
+  }
+
+  #[test]
+  fn round_robin_rotates() {
+    let servers = ServerList::new("a,b,c", Selection::RoundRobin, 3, Duration::ZERO);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+    assert_eq!(servers.candidates(), [1, 2, 0]);
+    assert_eq!(servers.candidates(), [2, 0, 1]);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+  }
+
+  #[test]
+  fn sticky_moves_on_failure() {
+    let servers = ServerList::new("a,b,c", Selection::Sticky, 3, Duration::ZERO);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+    servers.fail(0);
+    assert_eq!(servers.candidates(), [1, 2, 0]);
+    // a stale failure doesn't move the current node
+    servers.fail(0);
+    assert_eq!(servers.candidates(), [1, 2, 0]);
+  }
+
+  #[test]
+  fn ejected_nodes_are_last_resort() {
+    let servers = ServerList::new("a,b,c", Selection::Sticky, 2, Duration::from_secs(60));
+    servers.fail(1);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+    servers.fail(1);
+    assert_eq!(servers.candidates(), [0, 2, 1]);
+    // a success resets the failures
+    servers.succeed(1);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+    servers.fail(1);
+    assert_eq!(servers.candidates(), [0, 1, 2]);
+  }
+
+  #[test]
+  fn all_ejected_nodes_are_still_tried() {
+    let servers = ServerList::new("a,b", Selection::RoundRobin, 1, Duration::from_secs(60));
+    servers.fail(0);
+    servers.fail(1);
+    assert_eq!(servers.candidates(), [0, 1]);
+    assert_eq!(servers.candidates(), [1, 0]);
+  }
+
+  #[test]
+  fn ejection_expires() {
+    let servers = ServerList::new("a,b", Selection::RoundRobin, 1, Duration::ZERO);
+    servers.fail(0);
+    // ejected for no time, so it's not moved to the end
+    assert_eq!(servers.candidates(), [0, 1]);
+  }
+}